@@ -1,3 +1,5 @@
+use bevy::app::AppExit;
+use bevy::asset::LoadState;
 use bevy::input::keyboard::KeyboardInput;
 use bevy::prelude::*;
 use bevy_kira_audio::prelude::*;
@@ -16,14 +18,67 @@ use selected::{CurrentSelection, Selector};
 /// The random number generator we are using.
 pub type Random = rand_xoshiro::Xoroshiro128StarStar;
 
+/// The time given to answer each prompt (in seconds).
+const ANSWER_TIME: f32 = 10.0;
+
 /// Remaining time to answer (in seconds)
 pub struct RemainingTime(f32);
 pub struct AudioFlag(bool);
 
+/// The different states the game can be in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AppState {
+    /// The assets are being loaded.
+    Loading,
+    /// The player is in the main menu.
+    MainMenu,
+    /// The player is answering prompts.
+    Playing,
+    /// The story is over.
+    GameOver,
+    /// The credits are being displayed.
+    Credits,
+}
+
+/// The label of [`story_loop`], which moves the story to the next prompt.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, SystemLabel)]
+struct StoryLoop;
+
+/// A marker component for the root of the loading screen.
+#[derive(Component)]
+struct LoadingScreen;
+
+/// A marker component for the root of the main menu.
+#[derive(Component)]
+struct MenuScreen;
+
+/// A marker component for the root of the in-game ui.
+#[derive(Component)]
+struct GameScreen;
+
+/// A marker component for the root of the game over screen.
+#[derive(Component)]
+struct GameOverScreen;
+
+/// A marker component for the root of the credits screen.
+#[derive(Component)]
+struct CreditsScreen;
+
+/// A **component** that is added to the buttons of the main menu.
+#[derive(Clone, Copy, Component, Debug)]
+enum MenuButton {
+    Play,
+    Credits,
+    Quit,
+}
+
 /// Resource referencing every ui element
 struct UiElements {
     terminal: Entity,
-    choices: [Entity; 2],
+    /// The parent of the buttons of the current prompt.
+    choices: Entity,
+    /// The font of the buttons.
+    choice_style: TextStyle,
     timer: Entity,
 }
 
@@ -45,6 +100,12 @@ fn main() -> ExitCode {
         }
     };
 
+    let mut random = match p.seed {
+        Some(seed) => Random::seed_from_u64(seed),
+        None => Random::from_entropy(),
+    };
+    let executor = story::StoryExecutor::new(story, &mut random);
+
     App::new()
         .insert_resource(WindowDescriptor {
             title: "PROXIMA".to_string(),
@@ -53,24 +114,68 @@ fn main() -> ExitCode {
             resizable: false,
             ..default()
         })
-        .insert_resource(CurrentSelection(0))
-        .insert_resource(RemainingTime(10.0))
-        .insert_resource(story::StoryExecutor::from(story))
-        .insert_resource(Random::from_entropy())
+        .insert_resource(CurrentSelection(None))
+        .insert_resource(RemainingTime(ANSWER_TIME))
+        .insert_resource(executor)
+        .insert_resource(random)
         .insert_resource(AudioFlag(true))
         .insert_resource(Vec::<Handle<AudioSource>>::new())
         .add_plugins(DefaultPlugins)
         .add_plugin(AudioPlugin)
-        .add_startup_system(setup_scene)
+        .add_state(AppState::Loading)
+        .add_startup_system(setup_camera)
         .add_startup_system(setup_audio)
         .add_system_to_stage(CoreStage::First, ui::Prev::<Interaction>::update_prev)
         .add_system(ui::Terminal::animate_system)
-        .add_system(keyboard_events)
         .add_system(Selector::update_system)
-        .add_system(ui::Choice::select_choice_system)
-        .add_system(audio_game)
-        .add_system(story_loop)
-        .add_system(update_timer)
+        // The choices are respawned by the story loop: selecting them afterwards, in the same
+        // frame, would select answers of the previous prompt.
+        .add_system(ui::Choice::select_choice_system.before(StoryLoop))
+        .add_system(ui::Choice::keyboard_select_system.before(StoryLoop))
+        .add_system(play_story_sounds)
+        .add_system_set(SystemSet::on_enter(AppState::Loading).with_system(setup_loading))
+        .add_system_set(SystemSet::on_update(AppState::Loading).with_system(check_loading))
+        .add_system_set(
+            SystemSet::on_exit(AppState::Loading).with_system(despawn_screen::<LoadingScreen>),
+        )
+        .add_system_set(
+            SystemSet::on_enter(AppState::MainMenu)
+                .with_system(setup_menu)
+                .with_system(audio_menu),
+        )
+        .add_system_set(SystemSet::on_update(AppState::MainMenu).with_system(menu_input))
+        .add_system_set(
+            SystemSet::on_exit(AppState::MainMenu)
+                .with_system(despawn_screen::<MenuScreen>)
+                .with_system(stop_audio),
+        )
+        .add_system_set(SystemSet::on_enter(AppState::Playing).with_system(setup_scene))
+        .add_system_set(
+            SystemSet::on_update(AppState::Playing)
+                .with_system(audio_game)
+                .with_system(keyboard_events)
+                .with_system(story_loop.label(StoryLoop))
+                .with_system(update_timer),
+        )
+        .add_system_set(
+            SystemSet::on_exit(AppState::Playing).with_system(despawn_screen::<GameScreen>),
+        )
+        .add_system_set(SystemSet::on_enter(AppState::GameOver).with_system(setup_game_over))
+        .add_system_set(SystemSet::on_update(AppState::GameOver).with_system(game_over_input))
+        .add_system_set(
+            SystemSet::on_exit(AppState::GameOver).with_system(despawn_screen::<GameOverScreen>),
+        )
+        .add_system_set(
+            SystemSet::on_enter(AppState::Credits)
+                .with_system(setup_credits)
+                .with_system(audio_credits),
+        )
+        .add_system_set(SystemSet::on_update(AppState::Credits).with_system(credits_input))
+        .add_system_set(
+            SystemSet::on_exit(AppState::Credits)
+                .with_system(despawn_screen::<CreditsScreen>)
+                .with_system(stop_audio),
+        )
         .run();
 
     ExitCode::SUCCESS
@@ -122,6 +227,21 @@ fn audio_credits(audio: Res<Audio>, audio_assets: Res<Vec<Handle<AudioSource>>>)
     audio.play(audio_assets[1].clone()).looped();
 }
 
+fn stop_audio(audio: Res<Audio>) {
+    audio.stop();
+}
+
+/// Plays the sounds requested by the story, such as alarms.
+fn play_story_sounds(
+    audio: Res<Audio>,
+    assets: Res<AssetServer>,
+    mut executor: ResMut<story::StoryExecutor>,
+) {
+    for sound in executor.drain_sounds() {
+        audio.play(assets.load(&sound)).with_volume(VOLUME);
+    }
+}
+
 const VOLUME: f64 = 0.05;
 
 fn audio_game(
@@ -446,15 +566,39 @@ const BAR_W: f32 = 191.0;
 const BAR_X: f32 = 26.0;
 const BAR_Y: f32 = 11.0;
 const BAR_H: f32 = 16.0;
-const CHOICE_X1: f32 = 230.0;
-const CHOICE_Y1: f32 = 335.0;
-const CHOICE_X2: f32 = 230.0;
-const CHOICE_Y2: f32 = 407.0;
-
-fn setup_scene(mut commands: Commands, assets: Res<AssetServer>, story: Res<story::StoryExecutor>) {
-    let terminal_font = assets.load("RobotoMono-Medium.ttf");
+const CHOICE_X: f32 = 230.0;
+const CHOICE_Y: f32 = 335.0;
+const CHOICE_W: f32 = 490.0;
+const CHOICE_H: f32 = 60.0;
+/// The vertical room available to the buttons, starting at `CHOICE_Y`.
+const CHOICES_H: f32 = 144.0;
+/// The space between two buttons.
+const CHOICE_GAP: f32 = 12.0;
+/// The smallest font size answers are shrunk to when they do not fit.
+const CHOICE_MIN_FONT: f32 = 14.0;
+/// The width of a character of the monospace terminal font, relative to its size.
+const CHAR_W: f32 = 0.6;
+/// The height of a line of text, relative to the font size.
+const LINE_H: f32 = 1.2;
 
+fn setup_camera(mut commands: Commands) {
     commands.spawn_bundle(Camera2dBundle::default());
+}
+
+/// Despawns every entity marked with `T`, along with their children.
+fn despawn_screen<T: Component>(mut commands: Commands, query: Query<Entity, With<T>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn setup_scene(
+    mut commands: Commands,
+    assets: Res<AssetServer>,
+    story: Res<story::StoryExecutor>,
+    mut current_selection: ResMut<CurrentSelection>,
+) {
+    let terminal_font = assets.load("RobotoMono-Medium.ttf");
 
     let query_text_style = TextStyle {
         color: Color::WHITE,
@@ -468,16 +612,13 @@ fn setup_scene(mut commands: Commands, assets: Res<AssetServer>, story: Res<stor
         font_size: 24.0,
     };
 
-    let prompt = story.get_current_prompt().unwrap();
-
     let style = Style {
         size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
         position_type: PositionType::Absolute,
         ..default()
     };
 
-    let mut choice1 = Entity::from_raw(0); // TODO remove this hack
-    let mut choice2 = Entity::from_raw(0); // TODO remove this hack // TODO: don't remove it it's cool // TODO ok maybe don't remove it
+    let mut choices = Entity::from_raw(0); // TODO remove this hack // TODO: don't remove it it's cool // TODO ok maybe don't remove it
     let mut terminal = Entity::from_raw(0); // TODO remove this hack
     let mut timer = Entity::from_raw(0); // TODO remove this hack
 
@@ -487,6 +628,7 @@ fn setup_scene(mut commands: Commands, assets: Res<AssetServer>, story: Res<stor
             image: UiImage(assets.load("BackgroundStarsLoop.png")),
             ..default()
         })
+        .insert(GameScreen)
         .with_children(|parent| {
             parent
                 .spawn_bundle(ImageBundle {
@@ -507,7 +649,7 @@ fn setup_scene(mut commands: Commands, assets: Res<AssetServer>, story: Res<stor
                             style: Style {
                                 position_type: PositionType::Absolute,
                                 position: UiRect {
-                                    top: Val::Percent(0.0),
+                                    top: Val::Percent(-1000.0),
                                     left: Val::Percent(460.0 / 1896.0 * 100.0),
                                     ..default()
                                 },
@@ -553,96 +695,25 @@ fn setup_scene(mut commands: Commands, assets: Res<AssetServer>, story: Res<stor
                         .id();
                 });
 
-            parent
-                .spawn_bundle(ui::ChoiceBundle {
-                    choice: ui::Choice(1),
+            choices = parent
+                .spawn_bundle(NodeBundle {
                     style: Style {
                         position_type: PositionType::Absolute,
-                        position: UiRect {
-                            left: Val::Px(CHOICE_X1),
-                            top: Val::Px(CHOICE_Y1),
-                            ..default()
-                        },
-                        size: Size::new(Val::Px(490.0), Val::Px(60.0)),
                         ..default()
                     },
+                    color: UiColor(Color::NONE),
                     ..default()
                 })
-                .with_children(|children| {
-                    choice1 = children
-                        .spawn_bundle(ui::TerminalBundle {
-                            terminal: ui::Terminal {
-                                style: button_text_style.clone(),
-                                animated_text: prompt.answers[1].text.clone(),
-                                animation_index: 0,
-                                animation_period_range: (0.02, 0.04),
-                                next_animation_time: 0.0,
-                            },
-                            text: TextBundle {
-                                style: Style {
-                                    position_type: PositionType::Absolute,
-                                    position: UiRect {
-                                        left: Val::Px(0.0),
-                                        top: Val::Px(0.0),
-                                        ..default()
-                                    },
-                                    max_size: Size::new(Val::Px(490.0), Val::Px(60.0)),
-                                    ..default()
-                                },
-                                ..default()
-                            },
-                        })
-                        .id();
-                });
-
-            parent
-                .spawn_bundle(ui::ChoiceBundle {
-                    choice: ui::Choice(2),
-                    style: Style {
-                        position_type: PositionType::Absolute,
-                        position: UiRect {
-                            left: Val::Px(CHOICE_X2),
-                            top: Val::Px(CHOICE_Y2),
-                            ..default()
-                        },
-                        size: Size::new(Val::Px(490.0), Val::Px(60.0)),
-                        ..default()
-                    },
-                    ..default()
+                .with_children(|parent| {
+                    spawn_choices(parent, &button_text_style, &story, &mut current_selection);
                 })
-                .with_children(|children| {
-                    choice2 = children
-                        .spawn_bundle(ui::TerminalBundle {
-                            terminal: ui::Terminal {
-                                style: button_text_style.clone(),
-                                animated_text: prompt.answers[2].text.clone(),
-                                animation_index: 0,
-                                animation_period_range: (0.02, 0.04),
-                                next_animation_time: 0.0,
-                            },
-                            text: TextBundle {
-                                style: Style {
-                                    position_type: PositionType::Absolute,
-                                    position: UiRect {
-                                        left: Val::Px(0.0),
-                                        top: Val::Px(0.0),
-                                        ..default()
-                                    },
-                                    max_size: Size::new(Val::Px(490.0), Val::Px(60.0)),
-                                    ..default()
-                                },
-                                ..default()
-                            },
-                            ..default()
-                        })
-                        .id();
-                });
+                .id();
 
             terminal = parent
                 .spawn_bundle(ui::TerminalBundle {
                     terminal: ui::Terminal {
                         style: query_text_style,
-                        animated_text: prompt.request.clone(),
+                        animated_text: story.render_text(story.current_request()),
                         animation_index: 0,
                         animation_period_range: (0.02, 0.04),
                         next_animation_time: 0.0,
@@ -666,12 +737,154 @@ fn setup_scene(mut commands: Commands, assets: Res<AssetServer>, story: Res<stor
 
     commands.insert_resource(UiElements {
         terminal,
-        choices: [choice1, choice2],
+        choices,
+        choice_style: button_text_style,
         timer,
     });
 }
 
+/// Spawns a button for each visible answer of the current prompt, from top to bottom, and clears
+/// the selection, which referred to the previous buttons.
+fn spawn_choices(
+    parent: &mut ChildBuilder,
+    style: &TextStyle,
+    executor: &story::StoryExecutor,
+    selection: &mut CurrentSelection,
+) {
+    selection.0 = None;
+    let prompt = match executor.get_current_prompt() {
+        Some(prompt) => prompt,
+        None => return,
+    };
+    let answers = executor.visible_answers();
+    let texts: Vec<String> = answers
+        .iter()
+        .map(|&index| executor.render_text(&prompt.answers[index].text))
+        .collect();
+    let (font_size, height, spacing) = layout_choices(&texts, style.font_size);
+    let style = TextStyle {
+        font_size,
+        ..style.clone()
+    };
+
+    for (slot, (&index, text)) in answers.iter().zip(texts).enumerate() {
+        parent
+            .spawn_bundle(ui::ChoiceBundle {
+                choice: ui::Choice(index),
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    position: UiRect {
+                        left: Val::Px(CHOICE_X),
+                        top: Val::Px(CHOICE_Y + slot as f32 * spacing),
+                        ..default()
+                    },
+                    size: Size::new(Val::Px(CHOICE_W), Val::Px(height)),
+                    ..default()
+                },
+                ..default()
+            })
+            .with_children(|children| {
+                children.spawn_bundle(ui::TerminalBundle {
+                    terminal: ui::Terminal {
+                        style: style.clone(),
+                        animated_text: text,
+                        animation_index: 0,
+                        animation_period_range: (0.02, 0.04),
+                        next_animation_time: 0.0,
+                    },
+                    text: TextBundle {
+                        style: Style {
+                            position_type: PositionType::Absolute,
+                            position: UiRect {
+                                left: Val::Px(0.0),
+                                top: Val::Px(0.0),
+                                ..default()
+                            },
+                            max_size: Size::new(Val::Px(CHOICE_W), Val::Px(height)),
+                            ..default()
+                        },
+                        ..default()
+                    },
+                });
+            });
+    }
+}
+
+/// Chooses the font size, the height and the spacing of the buttons showing `texts`.
+///
+/// Buttons keep their usual spacing, and are squeezed when there are more than two of them. The
+/// font is made smaller until every answer fits in its button; when they still do not fit, the
+/// buttons grow to the height of their text and take more room than usual.
+fn layout_choices(texts: &[String], font_size: f32) -> (f32, f32, f32) {
+    let needed = |font_size: f32| {
+        let columns = (CHOICE_W / (CHAR_W * font_size)) as usize;
+        let lines = texts
+            .iter()
+            .map(|text| line_count(text, columns))
+            .max()
+            .unwrap_or(1);
+        lines as f32 * font_size * LINE_H
+    };
+
+    let spacing = CHOICES_H / texts.len().max(2) as f32;
+    let height = CHOICE_H.min(spacing - CHOICE_GAP);
+    let mut size = font_size;
+    while size >= CHOICE_MIN_FONT {
+        if needed(size) <= height {
+            return (size, height, spacing);
+        }
+        size -= 2.0;
+    }
+
+    let height = needed(CHOICE_MIN_FONT);
+    (CHOICE_MIN_FONT, height, height + CHOICE_GAP)
+}
+
+/// Counts the lines `text` takes once wrapped at `columns` characters.
+fn line_count(text: &str, columns: usize) -> usize {
+    text.split('\n')
+        .map(|line| wrapped_lines(line, columns))
+        .sum()
+}
+
+/// Counts the lines a single line of text takes once wrapped at `columns` characters.
+fn wrapped_lines(line: &str, columns: usize) -> usize {
+    let columns = columns.max(1);
+    let mut lines = 1;
+    let mut width = 0;
+    for word in line.split_whitespace() {
+        let length = word.chars().count();
+        if width > 0 && width + 1 + length > columns {
+            lines += 1;
+            width = 0;
+        }
+        if width > 0 {
+            width += 1;
+        }
+        width += length;
+        // Words longer than a line are broken.
+        while width > columns {
+            lines += 1;
+            width -= columns;
+        }
+    }
+    lines
+}
+
+/// How the player is answering the current prompt.
+#[derive(Default)]
+struct Answering {
+    /// The last answer the player chose.
+    last: Option<usize>,
+    /// The time that was left when the player last chose an answer.
+    selected_at: f32,
+    /// The number of times the player switched to another answer.
+    hesitations: usize,
+}
+
+#[allow(clippy::too_many_arguments)]
 fn story_loop(
+    mut commands: Commands,
     mut executor: ResMut<story::StoryExecutor>,
     mut current_selection: ResMut<CurrentSelection>,
     mut remaining_time: ResMut<RemainingTime>,
@@ -680,40 +893,74 @@ fn story_loop(
     dt: Res<Time>,
     mut query: Query<(&mut ui::Terminal, &mut Text)>,
     mut audio_flag: ResMut<AudioFlag>,
+    mut state: ResMut<State<AppState>>,
+    mut answering: Local<Answering>,
 ) {
     remaining_time.0 -= dt.delta_seconds();
 
+    if current_selection.is_changed() {
+        if let Some(choice) = current_selection.0 {
+            if matches!(answering.last, Some(last) if last != choice) {
+                answering.hesitations += 1;
+            }
+            answering.last = Some(choice);
+            answering.selected_at = remaining_time.0.max(0.0);
+        }
+    }
     if remaining_time.0 > 0.0 {
         return;
     }
     audio_flag.0 = true;
-    remaining_time.0 = 10.0;
-    let next_prompt = executor
-        .select_answer(current_selection.0, &mut *random)
-        .unwrap();
-    current_selection.0 = 0;
+    remaining_time.0 = ANSWER_TIME;
+    let answering = std::mem::take(&mut *answering);
+    let remaining = match current_selection.0 {
+        Some(_) => answering.selected_at,
+        None => 0.0,
+    };
+    let timing = story::Timing {
+        remaining,
+        reaction_time: (ANSWER_TIME - remaining).max(0.0),
+        hesitations: answering.hesitations,
+    };
+    if executor
+        .select_answer(current_selection.0, timing, &mut *random)
+        .is_none()
+    {
+        current_selection.0 = None;
+        let _ = state.set(AppState::GameOver);
+        return;
+    }
+    let follow_up = executor.take_follow_up();
+    let request = executor.render_text(executor.current_request());
     let (mut terminal, mut text) = query.get_mut(ui_elements.terminal).unwrap();
-    terminal.animated_text = next_prompt.request.clone();
+    terminal.animated_text = match follow_up {
+        // The outcome of the answer is told before the next request.
+        Some(follow_up) => format!("{}\n\n{request}", executor.render_text(&follow_up)),
+        None => request,
+    };
     terminal.animation_index = 0;
     text.sections
         .iter_mut()
         .map(|s| &mut s.value)
         .for_each(String::clear);
-    for (i, choice) in ui_elements.choices.iter().enumerate() {
-        let (mut choice, mut text) = query.get_mut(*choice).unwrap();
-        choice.animated_text = next_prompt.answers[i + 1].text.clone();
-        choice.animation_index = 0;
-        text.sections
-            .iter_mut()
-            .map(|s| &mut s.value)
-            .for_each(String::clear);
-    }
+
+    let mut choices = commands.entity(ui_elements.choices);
+    choices.despawn_descendants();
+    choices.with_children(|parent| {
+        spawn_choices(
+            parent,
+            &ui_elements.choice_style,
+            &executor,
+            &mut current_selection,
+        );
+    });
 }
 
 fn keyboard_events(
     mut key_evr: EventReader<KeyboardInput>,
     mut windows: ResMut<Windows>,
     mut time: ResMut<RemainingTime>,
+    #[cfg(debug_assertions)] executor: Res<story::StoryExecutor>,
 ) {
     use bevy::input::ButtonState;
     use bevy::window::WindowMode;
@@ -738,17 +985,255 @@ fn keyboard_events(
                 Some(KeyCode::Return) => {
                     time.0 = 0.0;
                 }
+                #[cfg(debug_assertions)]
+                Some(KeyCode::D) => print_story_state(&executor),
                 _ => {}
             },
         }
     }
 }
 
+/// Prints the variables of the story, its delayed actions and the answers given so far.
+#[cfg(debug_assertions)]
+fn print_story_state(executor: &story::StoryExecutor) {
+    for (name, value) in executor.variables().iter() {
+        println!("{name} = {value}");
+    }
+    for pending in executor.pending_actions() {
+        let action = serde_json::to_string(&pending.action).unwrap_or_default();
+        println!("in {} answers: {action}", pending.remaining);
+    }
+    for record in executor.history() {
+        match record.answer {
+            Some(answer) => println!("{}: answer {}", record.prompt, answer + 1),
+            None => println!("{}: timeout", record.prompt),
+        }
+    }
+}
+
 fn update_timer(
     timer: ResMut<RemainingTime>,
     ui_elements: Res<UiElements>,
     mut ui_query: Query<&mut Style>,
 ) {
     let mut bar = ui_query.get_mut(ui_elements.timer).unwrap();
-    bar.size.width = Val::Px(BAR_W * timer.0 / 10.0);
+    bar.size.width = Val::Px(BAR_W * timer.0 / ANSWER_TIME);
+}
+
+/// Spawns a full-screen `image` over the background stars, marked with `marker`.
+fn spawn_screen(
+    commands: &mut Commands,
+    assets: &AssetServer,
+    image: &str,
+    marker: impl Component,
+) -> Entity {
+    let style = Style {
+        size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+        position_type: PositionType::Absolute,
+        ..default()
+    };
+
+    commands
+        .spawn_bundle(ImageBundle {
+            style: style.clone(),
+            image: UiImage(assets.load("BackgroundStarsLoop.png")),
+            ..default()
+        })
+        .insert(marker)
+        .with_children(|parent| {
+            parent.spawn_bundle(ImageBundle {
+                style,
+                image: UiImage(assets.load(image)),
+                ..default()
+            });
+        })
+        .id()
+}
+
+/// Returns whether the player asked to move on to the next screen.
+///
+/// The inputs are consumed so that the next screen does not get skipped during the same frame.
+fn skip_requested(mouse: &mut Input<MouseButton>, keys: &mut Input<KeyCode>) -> bool {
+    let requested = mouse.just_pressed(MouseButton::Left)
+        || keys.just_pressed(KeyCode::Space)
+        || keys.just_pressed(KeyCode::Return);
+    if requested {
+        mouse.clear();
+        keys.clear();
+    }
+    requested
+}
+
+fn setup_loading(mut commands: Commands, assets: Res<AssetServer>) {
+    commands
+        .spawn_bundle(ImageBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                position_type: PositionType::Absolute,
+                ..default()
+            },
+            image: UiImage(assets.load("Loading.jpg")),
+            ..default()
+        })
+        .insert(LoadingScreen);
+}
+
+/// Waits for the audio assets to be loaded before showing the main menu.
+fn check_loading(
+    assets: Res<AssetServer>,
+    audio_assets: Res<Vec<Handle<AudioSource>>>,
+    mut state: ResMut<State<AppState>>,
+) {
+    match assets.get_group_load_state(audio_assets.iter().map(|h| h.id)) {
+        LoadState::Loaded | LoadState::Failed => {
+            let _ = state.set(AppState::MainMenu);
+        }
+        _ => {}
+    }
+}
+
+fn setup_menu(mut commands: Commands, assets: Res<AssetServer>) {
+    let menu = spawn_screen(&mut commands, &assets, "MenuText.png", MenuScreen);
+
+    // Invisible buttons laid over the entries of `MenuText.png`.
+    let buttons = [
+        (MenuButton::Play, 51.5),
+        (MenuButton::Credits, 61.0),
+        (MenuButton::Quit, 70.5),
+    ];
+
+    commands.entity(menu).with_children(|parent| {
+        for (button, top) in buttons {
+            parent
+                .spawn_bundle(ButtonBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        position: UiRect {
+                            left: Val::Percent(42.0),
+                            top: Val::Percent(top),
+                            ..default()
+                        },
+                        size: Size::new(Val::Percent(16.0), Val::Percent(8.0)),
+                        ..default()
+                    },
+                    color: UiColor(Color::NONE),
+                    ..default()
+                })
+                .insert(button);
+        }
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn menu_input(
+    query: Query<(&Interaction, &MenuButton), Changed<Interaction>>,
+    mut keys: ResMut<Input<KeyCode>>,
+    mut executor: ResMut<story::StoryExecutor>,
+    mut random: ResMut<Random>,
+    mut remaining_time: ResMut<RemainingTime>,
+    mut audio_flag: ResMut<AudioFlag>,
+    mut state: ResMut<State<AppState>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let mut pressed = query
+        .iter()
+        .find(|(interaction, _)| **interaction == Interaction::Clicked)
+        .map(|(_, button)| *button);
+
+    if keys.just_pressed(KeyCode::Return) {
+        keys.clear();
+        pressed = Some(MenuButton::Play);
+    }
+
+    match pressed {
+        Some(MenuButton::Play) => {
+            executor.restart(&mut *random);
+            remaining_time.0 = ANSWER_TIME;
+            audio_flag.0 = true;
+            let _ = state.set(AppState::Playing);
+        }
+        Some(MenuButton::Credits) => {
+            let _ = state.set(AppState::Credits);
+        }
+        Some(MenuButton::Quit) => exit.send(AppExit),
+        None => {}
+    }
+}
+
+fn setup_game_over(
+    mut commands: Commands,
+    assets: Res<AssetServer>,
+    executor: Res<story::StoryExecutor>,
+) {
+    let ending = executor.ending();
+    let image = ending
+        .and_then(|ending| ending.image.as_deref())
+        .unwrap_or("GameOver2.png");
+    let screen = spawn_screen(&mut commands, &assets, image, GameOverScreen);
+
+    let ending = match ending {
+        Some(ending) => ending,
+        None => return,
+    };
+
+    let font = assets.load("RobotoMono-Medium.ttf");
+    let texts = [
+        (executor.render_text(&ending.title), 34.0, 15.0),
+        (executor.render_text(&ending.text), 24.0, 65.0),
+    ];
+
+    commands.entity(screen).with_children(|parent| {
+        for (text, font_size, top) in texts {
+            parent.spawn_bundle(ui::TerminalBundle {
+                terminal: ui::Terminal {
+                    style: TextStyle {
+                        color: Color::WHITE,
+                        font: font.clone(),
+                        font_size,
+                    },
+                    animated_text: text,
+                    animation_index: 0,
+                    animation_period_range: (0.02, 0.04),
+                    next_animation_time: 0.0,
+                },
+                text: TextBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        position: UiRect {
+                            left: Val::Percent(20.0),
+                            top: Val::Percent(top),
+                            ..default()
+                        },
+                        max_size: Size::new(Val::Percent(60.0), Val::Undefined),
+                        ..default()
+                    },
+                    ..default()
+                },
+            });
+        }
+    });
+}
+
+fn game_over_input(
+    mut mouse: ResMut<Input<MouseButton>>,
+    mut keys: ResMut<Input<KeyCode>>,
+    mut state: ResMut<State<AppState>>,
+) {
+    if skip_requested(&mut mouse, &mut keys) {
+        let _ = state.set(AppState::Credits);
+    }
+}
+
+fn setup_credits(mut commands: Commands, assets: Res<AssetServer>) {
+    spawn_screen(&mut commands, &assets, "Credits.png", CreditsScreen);
+}
+
+fn credits_input(
+    mut mouse: ResMut<Input<MouseButton>>,
+    mut keys: ResMut<Input<KeyCode>>,
+    mut state: ResMut<State<AppState>>,
+) {
+    if skip_requested(&mut mouse, &mut keys) {
+        let _ = state.set(AppState::MainMenu);
+    }
 }
@@ -20,6 +20,8 @@ pub struct TypingSpeed {
 pub struct Config {
     pub typing_speed: TypingSpeed,
     pub window_size: WindowSize,
+    /// The seed of the random number generator. A random seed is used when it is not set.
+    pub seed: Option<u64>,
 }
 
 #[cfg(any(not(debug_assertions), target_arch = "wasm32"))]
@@ -1,31 +1,39 @@
 use bevy::prelude::*;
 
+use crate::ui::Choice;
+
 /// A **resource** that represent the user's current selection.
-pub struct CurrentSelection(pub usize);
+///
+/// This is the index of the selected answer in the current prompt, if any.
+pub struct CurrentSelection(pub Option<usize>);
 
 /// A marker component for the entity that's responsible for selecting a button.
 #[derive(Clone, Copy, Component, Debug)]
 pub struct Selector;
 
-const CHOICE_Y1: f32 = 335.0; // TODO: make good code for once
-const CHOICE_Y2: f32 = 407.0;
-
 impl Selector {
     pub fn update_system(
         mut query: Query<&mut Style, With<Selector>>,
+        choices: Query<(&Choice, &Style), Without<Selector>>,
         current: Res<CurrentSelection>,
     ) {
         if !current.is_changed() {
             return;
         }
 
+        // The selector is moved over the button that shows the selected answer.
+        let selected = choices
+            .iter()
+            .find(|(choice, _)| Some(choice.0) == current.0)
+            .map(|(_, style)| (style.position.top, style.size));
+
         for mut style in query.iter_mut() {
-            println!("selected: {}", current.0);
-            match current.0 {
-                0 => style.position.top = Val::Percent(-1000.0),
-                1 => style.position.top = Val::Px(CHOICE_Y1 + 5.0),
-                2 => style.position.top = Val::Px(CHOICE_Y2 + 5.0),
-                _ => (),
+            match selected {
+                Some((Val::Px(top), size)) => {
+                    style.position.top = Val::Px(top + 5.0);
+                    style.size = size;
+                }
+                _ => style.position.top = Val::Percent(-1000.0),
             }
         }
     }
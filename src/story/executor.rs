@@ -1,84 +1,1224 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
 use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use super::{Action, Batch, Condition, Effect, Ending, Prompt, Story, Target, Value, Variables};
+
+/// Where the story should go after an answer, when it does not simply move on.
+#[derive(Clone, Copy, Debug)]
+enum Redirect {
+    /// The story must end.
+    End,
+    /// The story must continue from a labelled batch or prompt.
+    Jump(Target),
+    /// The story must play a labelled batch or prompt, then come back.
+    Call(Target),
+}
 
-use super::{Prompt, Story, Variables};
+/// The maximum number of nested calls.
+const MAX_CALL_DEPTH: usize = 8;
+
+/// An action waiting for its delay to be over.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingAction {
+    /// The number of answers left before the action is applied.
+    pub remaining: usize,
+    /// The action to apply.
+    pub action: Action,
+}
+
+/// How quickly the player answered a prompt.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Timing {
+    /// The time that was left to answer, in seconds.
+    pub remaining: f32,
+    /// The time the player took to settle on their answer, in seconds.
+    pub reaction_time: f32,
+    /// The number of times the player changed their answer after first choosing one.
+    pub hesitations: usize,
+}
+
+/// A prompt that was presented to the player, and how it was answered.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    /// The key of the prompt: its label, or its request when it has none.
+    pub prompt: String,
+    /// The index of the chosen answer, or `None` when the time ran out.
+    pub answer: Option<usize>,
+    /// How quickly the answer was given.
+    pub timing: Timing,
+}
+
+/// A position to come back to once a called batch is exhausted.
+#[derive(Clone, Debug)]
+struct Frame {
+    batch: usize,
+    prompt: usize,
+    order: Vec<usize>,
+    presented: bool,
+    /// Whether the prompt at this position still has to be presented, rather than being the one
+    /// that made the call.
+    resume: bool,
+}
+
+/// Checks a condition, reporting evaluation errors as unmet conditions.
+fn holds(condition: &Condition, variables: &Variables) -> bool {
+    condition.check(variables).unwrap_or_else(|err| {
+        eprintln!("error: {err}");
+        false
+    })
+}
+
+/// Finds the target of a jump, reporting unknown labels.
+fn target(labels: &HashMap<String, Target>, id: &str) -> Option<Target> {
+    let target = labels.get(id).copied();
+    if target.is_none() {
+        eprintln!("error: no batch or prompt is labelled `{id}`");
+    }
+    target
+}
+
+/// Picks an index at random, each index being as likely as its weight. Indices without a positive
+/// weight are never picked.
+fn pick_weighted(weights: &[f64], rng: &mut dyn RngCore) -> Option<usize> {
+    let total: f64 = weights.iter().filter(|&&weight| weight > 0.0).sum();
+    if total <= 0.0 || total.is_nan() {
+        return None;
+    }
+
+    let mut roll = rng.gen_range(0.0..total);
+    let last = weights.iter().rposition(|&weight| weight > 0.0);
+    weights
+        .iter()
+        .position(|&weight| {
+            if weight <= 0.0 {
+                return false;
+            }
+            roll -= weight;
+            roll < 0.0
+        })
+        .or(last)
+}
 
 /// A **resource** that's responsible for executing the story's logic.
 pub struct StoryExecutor {
     story: Story,
     pub current_batch: usize,
     pub current_prompt: usize,
+    /// The order in which the prompts of the current batch are presented: `current_prompt` is a
+    /// position in this list.
+    order: Vec<usize>,
+    /// Whether a prompt has been presented since the current batch was entered.
+    presented: bool,
+    /// The variant of the request of the current prompt that was picked when it was presented.
+    request: String,
+    /// The keys of the play-once prompts that have been presented.
+    consumed_prompts: HashSet<String>,
+    /// The groups of which a prompt has been presented.
+    consumed_groups: HashSet<String>,
     variables: Variables,
+    /// The positions to come back to, from the outermost call to the innermost one.
+    stack: Vec<Frame>,
+    /// The text of the outcome of the last answer, if it has one.
+    follow_up: Option<String>,
+    /// The batches and prompts to play before the next prompt.
+    queue: VecDeque<Target>,
+    /// The delayed actions, in the order they were scheduled.
+    pending: Vec<PendingAction>,
+    /// The prompts answered so far, from the first one to the last one.
+    history: Vec<Record>,
+    /// The number of answers before each event can happen again.
+    cooldowns: Vec<usize>,
+    /// Whether the condition of each trigger held the last time it was checked.
+    triggers_state: Vec<bool>,
+    /// Whether each trigger that only fires once has fired.
+    triggers_spent: Vec<bool>,
+    /// The sounds requested by the story that have not been played yet.
+    sounds: Vec<String>,
 }
 
 impl StoryExecutor {
+    /// Creates a new [`StoryExecutor`], ready to present the first prompt of the story.
+    pub fn new(s: Story, rng: &mut dyn RngCore) -> Self {
+        let mut executor = Self {
+            story: s,
+            current_batch: 0,
+            current_prompt: 0,
+            order: Vec::new(),
+            presented: false,
+            request: String::new(),
+            consumed_prompts: HashSet::new(),
+            consumed_groups: HashSet::new(),
+            variables: Variables::default(),
+            stack: Vec::new(),
+            follow_up: None,
+            queue: VecDeque::new(),
+            pending: Vec::new(),
+            history: Vec::new(),
+            cooldowns: Vec::new(),
+            triggers_state: Vec::new(),
+            triggers_spent: Vec::new(),
+            sounds: Vec::new(),
+        };
+        executor.restart(rng);
+        executor
+    }
+
     /// Gets the current prompt.
     pub fn get_current_prompt(&self) -> Option<&Prompt> {
-        let batch = self.story.batches.get(self.current_batch)?;
-        Some(&batch.prompts[self.current_prompt])
+        if self.is_finished() {
+            return None;
+        }
+        let batch = self.batch(self.current_batch)?;
+        batch.prompts.get(*self.order.get(self.current_prompt)?)
     }
 
-    /// Selects a specific answer.
-    pub fn select_answer(&mut self, choice: usize, rng: &mut dyn RngCore) -> Option<&Prompt> {
-        for action in self.story.batches[self.current_batch].prompts[self.current_prompt].answers
-            [choice]
-            .actions
+    /// Gets the request of the current prompt, in the variant that was picked for it.
+    pub fn current_request(&self) -> &str {
+        &self.request
+    }
+
+    /// Gets the indices of the answers of the current prompt that are offered to the player.
+    pub fn visible_answers(&self) -> Vec<usize> {
+        let prompt = match self.get_current_prompt() {
+            Some(prompt) => prompt,
+            None => return Vec::new(),
+        };
+
+        prompt
+            .answers
             .iter()
-        {
-            let val = self.variables.get_mut(&action.name);
-            action.op.execute(val, action.value);
+            .enumerate()
+            .filter(|(_, answer)| self.allows(&answer.condition))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Returns whether every batch of the story has been exhausted.
+    pub fn is_finished(&self) -> bool {
+        self.current_batch >= self.story.batches.len() && self.stack.is_empty()
+    }
+
+    /// Gets the ending the story reached, if it is finished.
+    pub fn ending(&self) -> Option<&Ending> {
+        if !self.is_finished() {
+            return None;
         }
 
-        // Find out which prompt should be used next.
-        loop {
-            let mut batch = self.story.batches.get_mut(self.current_batch)?;
+        self.story
+            .endings
+            .iter()
+            .find(|ending| self.allows(&ending.condition))
+    }
+
+    /// Gets the current state of the story's variables, for the debug view.
+    #[cfg(any(debug_assertions, test))]
+    pub fn variables(&self) -> &Variables {
+        &self.variables
+    }
+
+    /// Replaces the placeholders of a text of the story with the current values of the variables.
+    pub fn render_text(&self, text: &str) -> String {
+        super::render(text, &self.variables)
+    }
+
+    /// Takes the text of the outcome of the last answer, if it has one.
+    pub fn take_follow_up(&mut self) -> Option<String> {
+        self.follow_up.take()
+    }
+
+    /// Gets the delayed actions that have not been applied yet.
+    #[cfg(any(debug_assertions, test))]
+    pub fn pending_actions(&self) -> &[PendingAction] {
+        &self.pending
+    }
+
+    /// Gets the prompts answered so far, from the first one to the last one.
+    #[cfg(any(debug_assertions, test))]
+    pub fn history(&self) -> &[Record] {
+        &self.history
+    }
+
+    /// Takes the sounds requested by the story since the last call.
+    pub fn drain_sounds(&mut self) -> impl Iterator<Item = String> + '_ {
+        self.sounds.drain(..)
+    }
+
+    /// Rewinds the story to its first prompt, and resets the variables to their initial state:
+    /// their declared default, modified by the initial actions of the story.
+    pub fn restart(&mut self, rng: &mut dyn RngCore) {
+        self.variables = Variables::new(&self.story.variables, self.story.strict);
+        for (name, value) in self.story.builtins() {
+            self.variables.set_builtin(&name, value);
+        }
+        self.sounds.clear();
+        self.consumed_prompts.clear();
+        self.consumed_groups.clear();
+        self.pending.clear();
+        self.history.clear();
+        self.follow_up = None;
+
+        for action in self.story.actions.clone() {
+            if self.schedule(&action) {
+                continue;
+            }
+            if let Err(err) = action.apply(&mut self.variables, rng) {
+                eprintln!("error: {err}");
+            }
+        }
 
-            if self.current_prompt + 1 >= batch.prompts.len() {
-                // The batch has been exhausted.
-                self.current_batch += 1;
+        self.reset_triggers();
+        self.stack.clear();
+        self.queue.clear();
+        self.cooldowns = vec![0; self.story.events.len()];
+        self.enter_batch(self.next_batch(0), rng);
+        self.find_prompt(false, rng);
+    }
+
+    /// Selects a specific answer, or `None` when the player did not answer in time. An answer
+    /// that is not offered to the player, such as one of a previous prompt, counts as no answer.
+    ///
+    /// Returns the next prompt, or `None` once the story is finished.
+    pub fn select_answer(
+        &mut self,
+        choice: Option<usize>,
+        timing: Timing,
+        rng: &mut dyn RngCore,
+    ) -> Option<&Prompt> {
+        let choice = choice.filter(|choice| self.visible_answers().contains(choice));
+        // The variables describing the answer can fire triggers, before any action is applied.
+        let mut redirect = None;
+        self.record(choice, timing);
+        self.fire_triggers(&mut redirect);
+        let prompt = self.get_current_prompt()?;
+        let mut follow_up = None;
+        let (actions, goto, call) = match choice {
+            Some(choice) => {
+                let answer = &prompt.answers[choice];
+                let weights: Vec<f64> = answer
+                    .outcomes
+                    .iter()
+                    .map(|outcome| outcome.weight)
+                    .collect();
+                let outcome = pick_weighted(&weights, rng).map(|index| &answer.outcomes[index]);
+
+                let mut actions = answer.actions.clone();
+                actions.extend(outcome.iter().flat_map(|outcome| outcome.actions.clone()));
+                let goto = outcome.and_then(|outcome| outcome.goto.clone());
+                follow_up = outcome.and_then(|outcome| outcome.text.clone());
+                (
+                    actions,
+                    goto.or_else(|| answer.goto.clone()),
+                    answer.call.clone(),
+                )
+            }
+            None => (
+                prompt.on_timeout.actions.clone(),
+                prompt.on_timeout.goto.clone(),
+                None,
+            ),
+        };
+
+        self.follow_up = follow_up;
+
+        self.apply_pending(rng, &mut redirect);
+        for action in &actions {
+            if !self.schedule(action) {
+                self.apply_action(action, rng, &mut redirect);
+            }
+        }
+        if let Some(target) = goto.and_then(|id| target(&self.story.labels, &id)) {
+            redirect.get_or_insert(Redirect::Jump(target));
+        }
+        if let Some(target) = call.and_then(|id| target(&self.story.labels, &id)) {
+            redirect.get_or_insert(Redirect::Call(target));
+        }
+
+        match redirect {
+            Some(Redirect::End) => {
+                self.current_batch = self.story.batches.len();
                 self.current_prompt = 0;
-                batch = self.story.batches.get_mut(self.current_batch)?;
-            } else {
-                self.current_prompt += 1;
+                self.stack.clear();
+                return None;
+            }
+            Some(Redirect::Jump(target)) => {
+                // A jump leaves the calls being played: the story does not come back from them.
+                self.stack.clear();
+                self.jump(target, rng);
+                self.find_prompt(false, rng)?;
+            }
+            Some(Redirect::Call(target)) if self.can_call(target) => self.call(target, rng)?,
+            Some(Redirect::Call(_)) => self.find_prompt(true, rng)?,
+            None => match self.roll_event(rng) {
+                Some(event) => self.call(event, rng)?,
+                None => self.find_prompt(true, rng)?,
+            },
+        }
+
+        self.get_current_prompt()
+    }
+
+    /// Adds the answer to the current prompt to the history, and updates the variables that
+    /// describe it.
+    fn record(&mut self, choice: Option<usize>, timing: Timing) {
+        let prompt = match self.get_current_prompt() {
+            Some(prompt) => prompt,
+            None => return,
+        };
+        let key = prompt.key().to_owned();
+        let label = prompt.id.clone();
+        let text = choice.map(|choice| self.render_text(&prompt.answers[choice].text));
+
+        if let Some(id) = label {
+            let answer = choice.map_or(-1, |choice| choice as i64 + 1);
+            let text = Value::Text(text.unwrap_or_default());
+            self.variables
+                .set_builtin(&format!("_answer-{id}"), Value::Int(answer));
+            self.variables.set_builtin(&format!("_text-{id}"), text);
+        }
+
+        self.history.push(Record {
+            prompt: key,
+            answer: choice,
+            timing,
+        });
+        let timeouts = self
+            .history
+            .iter()
+            .filter(|record| record.answer.is_none())
+            .count();
+        self.variables
+            .set_builtin("_timeouts", Value::Int(timeouts as i64));
+        self.variables.set_builtin(
+            "_reaction_time",
+            Value::Float(f64::from(timing.reaction_time)),
+        );
+        self.variables
+            .set_builtin("_hesitations", Value::Int(timing.hesitations as i64));
+    }
+
+    /// Queues an action that has a delay. Returns whether the action was queued.
+    fn schedule(&mut self, action: &Action) -> bool {
+        if action.delay == 0 {
+            return false;
+        }
+
+        self.pending.push(PendingAction {
+            remaining: action.delay,
+            action: action.clone(),
+        });
+        true
+    }
+
+    /// Counts an answer for every delayed action, and applies the ones whose delay is over.
+    fn apply_pending(&mut self, rng: &mut dyn RngCore, redirect: &mut Option<Redirect>) {
+        for pending in &mut self.pending {
+            pending.remaining -= 1;
+        }
+
+        let (due, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(|pending| pending.remaining == 0);
+        self.pending = pending;
+
+        for pending in due {
+            self.apply_action(&pending.action, rng, redirect);
+        }
+    }
+
+    /// Applies an action, then fires the triggers whose condition just became true.
+    fn apply_action(
+        &mut self,
+        action: &Action,
+        rng: &mut dyn RngCore,
+        redirect: &mut Option<Redirect>,
+    ) {
+        if let Err(err) = action.apply(&mut self.variables, rng) {
+            eprintln!("error: {err}");
+        }
+        self.fire_triggers(redirect);
+    }
+
+    /// Fires the triggers whose condition just became true.
+    fn fire_triggers(&mut self, redirect: &mut Option<Redirect>) {
+        for (index, trigger) in self.story.triggers.iter().enumerate() {
+            let now = holds(&trigger.condition, &self.variables);
+            let fired = now && !self.triggers_state[index] && !self.triggers_spent[index];
+            self.triggers_state[index] = now;
+
+            if !fired {
+                continue;
             }
+            self.triggers_spent[index] = trigger.once;
 
-            if batch.randomized {
-                // Swap any element with the current first one.
-                let swapped_index = rng.gen_range(self.current_prompt..batch.prompts.len());
-                batch.prompts.swap(self.current_prompt, swapped_index);
+            for effect in &trigger.then {
+                match effect {
+                    Effect::End => *redirect = Some(Redirect::End),
+                    Effect::Goto(id) => {
+                        if let Some(target) = target(&self.story.labels, id) {
+                            redirect.get_or_insert(Redirect::Jump(target));
+                        }
+                    }
+                    Effect::Call(id) => {
+                        if let Some(target) = target(&self.story.labels, id) {
+                            redirect.get_or_insert(Redirect::Call(target));
+                        }
+                    }
+                    Effect::Queue(id) => self.queue.extend(target(&self.story.labels, id)),
+                    Effect::Play(sound) => self.sounds.push(sound.clone()),
+                }
             }
+        }
+    }
 
-            let prompt = &batch.prompts[self.current_prompt];
+    /// Plays a labelled batch or prompt, remembering where to come back to.
+    ///
+    /// Returns `None` once the story is finished.
+    fn call(&mut self, target: Target, rng: &mut dyn RngCore) -> Option<()> {
+        self.push_frame(false);
+        self.jump(target, rng);
+        self.find_prompt(false, rng)
+    }
 
-            if let Some(pre_condition) = prompt.pre_condition.as_ref() {
-                if !pre_condition
-                    .op
-                    .check(self.variables.get(&pre_condition.name), pre_condition.value)
+    /// Remembers the current position, to come back to it once a called batch is exhausted.
+    fn push_frame(&mut self, resume: bool) {
+        self.stack.push(Frame {
+            batch: self.current_batch,
+            prompt: self.current_prompt,
+            order: std::mem::take(&mut self.order),
+            presented: self.presented,
+            resume,
+        });
+    }
+
+    /// Decides whether a random event interrupts the story after an answer, and returns the batch
+    /// of that event.
+    fn roll_event(&mut self, rng: &mut dyn RngCore) -> Option<Target> {
+        // Events do not interrupt each other, and their answers do not count towards cooldowns.
+        let scripted = self.story.batches.len();
+        let playing = self.stack.iter().map(|frame| frame.batch);
+        if playing
+            .chain([self.current_batch])
+            .any(|batch| batch >= scripted)
+        {
+            return None;
+        }
+
+        let ready: Vec<bool> = self
+            .cooldowns
+            .iter_mut()
+            .map(|cooldown| {
+                let ready = *cooldown == 0;
+                *cooldown = cooldown.saturating_sub(1);
+                ready
+            })
+            .collect();
+
+        for (index, event) in self.story.events.iter().enumerate() {
+            if !ready[index] || !self.allows(&event.batch.condition) {
+                continue;
+            }
+
+            if rng.gen_bool(event.probability.clamp(0.0, 1.0)) {
+                self.cooldowns[index] = event.cooldown;
+                return Some(Target {
+                    batch: scripted + index,
+                    prompt: None,
+                });
+            }
+        }
+
+        None
+    }
+
+    /// Gets a batch of the story. The batches of the events come after the scripted ones.
+    fn batch(&self, index: usize) -> Option<&Batch> {
+        match index.checked_sub(self.story.batches.len()) {
+            None => self.story.batches.get(index),
+            Some(event) => self.story.events.get(event).map(|event| &event.batch),
+        }
+    }
+
+    /// Checks whether a batch can be called, reporting calls that nest too deeply or that would
+    /// loop.
+    fn can_call(&self, target: Target) -> bool {
+        if self.stack.len() >= MAX_CALL_DEPTH {
+            eprintln!("error: calls are nested more than {MAX_CALL_DEPTH} times");
+            return false;
+        }
+
+        let playing = self.stack.iter().map(|frame| frame.batch);
+        if playing
+            .chain([self.current_batch])
+            .any(|batch| batch == target.batch)
+        {
+            eprintln!(
+                "error: batch {} is already being played, calling it would loop",
+                target.batch
+            );
+            return false;
+        }
+
+        true
+    }
+
+    /// Records whether the condition of each trigger currently holds, so that they only fire once
+    /// it changes.
+    fn reset_triggers(&mut self) {
+        self.triggers_state = self
+            .story
+            .triggers
+            .iter()
+            .map(|trigger| holds(&trigger.condition, &self.variables))
+            .collect();
+        self.triggers_spent = vec![false; self.story.triggers.len()];
+    }
+
+    /// Moves to the first prompt of a batch, and decides the order of its prompts.
+    ///
+    /// A batch whose condition does not hold has no prompts to present.
+    fn enter_batch(&mut self, batch: usize, rng: &mut dyn RngCore) {
+        self.order = match self.batch(batch) {
+            Some(batch) if self.allows(&batch.condition) => match batch.draw {
+                Some(count) => self.draw(batch, count, rng),
+                None => (0..batch.prompts.len()).collect(),
+            },
+            _ => Vec::new(),
+        };
+
+        self.current_batch = batch;
+        self.current_prompt = 0;
+        self.presented = false;
+        self.shuffle(rng);
+    }
+
+    /// Finds the first batch, starting at `index`, that the story moves on to by itself: callable
+    /// batches are skipped.
+    fn next_batch(&self, index: usize) -> usize {
+        (index..self.story.batches.len())
+            .find(|&index| !self.story.batches[index].callable)
+            .unwrap_or_else(|| index.max(self.story.batches.len()))
+    }
+
+    /// Draws up to `count` prompts of a batch whose pre-condition holds. Prompts with a larger
+    /// weight are more likely to be drawn.
+    fn draw(&self, batch: &Batch, count: usize, rng: &mut dyn RngCore) -> Vec<usize> {
+        let mut pool: Vec<usize> = (0..batch.prompts.len())
+            .filter(|&index| {
+                let prompt = &batch.prompts[index];
+                prompt.weight > 0.0 && self.available(prompt)
+            })
+            .collect();
+
+        let mut drawn = Vec::new();
+        while drawn.len() < count {
+            let weights: Vec<f64> = pool
+                .iter()
+                .map(|&index| batch.prompts[index].weight)
+                .collect();
+            match pick_weighted(&weights, rng) {
+                Some(picked) => drawn.push(pool.swap_remove(picked)),
+                None => break,
+            }
+        }
+
+        // Fixed batches keep their order, only some prompts are left out.
+        if !batch.randomized {
+            drawn.sort_unstable();
+        }
+        drawn
+    }
+
+    /// Checks whether a prompt can be presented: its pre-condition must hold, and neither it nor
+    /// its group must have been consumed.
+    fn available(&self, prompt: &Prompt) -> bool {
+        let consumed = prompt.once && self.consumed_prompts.contains(prompt.key());
+        let grouped = prompt
+            .group
+            .iter()
+            .any(|group| self.consumed_groups.contains(group));
+
+        !consumed && !grouped && self.allows(&prompt.pre_condition)
+    }
+
+    /// Checks an optional condition, which holds when it is not specified.
+    fn allows(&self, condition: &Option<Condition>) -> bool {
+        condition
+            .iter()
+            .all(|condition| holds(condition, &self.variables))
+    }
+
+    /// In a randomized batch, swaps any prompt that has not been presented yet with the current
+    /// one.
+    fn shuffle(&mut self, rng: &mut dyn RngCore) {
+        let randomized = matches!(
+            self.batch(self.current_batch),
+            Some(batch) if batch.randomized
+        );
+
+        if randomized && self.current_prompt < self.order.len() {
+            let swapped_index = rng.gen_range(self.current_prompt..self.order.len());
+            self.order.swap(self.current_prompt, swapped_index);
+        }
+    }
+
+    /// Moves to a labelled batch or prompt.
+    fn jump(&mut self, target: Target, rng: &mut dyn RngCore) {
+        self.enter_batch(target.batch, rng);
+
+        if let Some(prompt) = target.prompt {
+            let randomized = self.batch(target.batch).unwrap().randomized;
+            match self.order.iter().position(|&index| index == prompt) {
+                Some(position) if !randomized => self.current_prompt = position,
+                // The target is presented first, the rest of the batch stays random.
+                Some(position) => self.order.swap(0, position),
+                // The target was not drawn, or its batch is skipped: it is presented anyway.
+                None => self.order.insert(0, prompt),
+            }
+        }
+    }
+
+    /// Moves to the first prompt whose pre-condition holds, starting at the current one. If
+    /// `advance` is set, the current prompt is skipped.
+    ///
+    /// Returns `None` once the story is finished.
+    fn find_prompt(&mut self, mut advance: bool, rng: &mut dyn RngCore) -> Option<()> {
+        loop {
+            if self.is_finished() {
+                return None;
+            }
+            let batch = self.batch(self.current_batch)?;
+
+            if !advance {
+                advance = true;
+            } else if self.current_prompt + 1 >= self.order.len() {
+                // The batch has been exhausted: the story plays it again, comes back from the last
+                // call, or moves on to the next batch. A batch that presented nothing is not
+                // repeated, as it would loop forever.
+                if self.presented
+                    && batch.repeat_while.is_some()
+                    && self.allows(&batch.repeat_while)
                 {
+                    self.enter_batch(self.current_batch, rng);
+                } else if let Some(frame) = self.stack.pop() {
+                    self.current_batch = frame.batch;
+                    self.current_prompt = frame.prompt;
+                    self.order = frame.order;
+                    self.presented = frame.presented;
+                    advance = !frame.resume;
                     continue;
+                } else {
+                    self.enter_batch(self.next_batch(self.current_batch + 1), rng);
                 }
+            } else {
+                self.current_prompt += 1;
+                self.shuffle(rng);
+            }
+
+            // Queued batches and prompts are played before the prompt the story reached, one after
+            // the other.
+            let playing_queued = self.stack.iter().any(|frame| frame.resume);
+            let queued = if playing_queued {
+                None
+            } else {
+                self.queue.pop_front()
+            };
+            if let Some(target) = queued.filter(|&target| self.can_call(target)) {
+                self.push_frame(true);
+                self.jump(target, rng);
+                advance = false;
+                continue;
             }
 
-            break;
+            // Empty batches have no current prompt, and are skipped.
+            let prompt = match self.get_current_prompt() {
+                Some(prompt) if self.available(prompt) => prompt,
+                _ => continue,
+            };
+
+            let key = prompt.once.then(|| prompt.key().to_owned());
+            let group = prompt.group.clone();
+            let request = self.pick_request(prompt, rng);
+            self.consumed_prompts.extend(key);
+            self.consumed_groups.extend(group);
+            self.request = request;
+            self.presented = true;
+            return Some(());
         }
+    }
 
-        self.get_current_prompt()
+    /// Picks a variant of the request of a prompt among the ones whose condition holds. Variants
+    /// with a larger weight are more likely to be picked, and the first variant is used when none
+    /// can be picked.
+    fn pick_request(&self, prompt: &Prompt, rng: &mut dyn RngCore) -> String {
+        // A single request is not rolled, so that it does not change the course of the story.
+        if let [variant] = prompt.request.as_slice() {
+            return variant.text.clone();
+        }
+
+        let weights: Vec<f64> = prompt
+            .request
+            .iter()
+            .map(|variant| {
+                if self.allows(&variant.condition) {
+                    variant.weight
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        let index = pick_weighted(&weights, rng).unwrap_or(0);
+        prompt
+            .request
+            .get(index)
+            .map(|variant| variant.text.clone())
+            .unwrap_or_default()
     }
 }
 
-impl From<Story> for StoryExecutor {
-    fn from(s: Story) -> Self {
-        let mut variables = Variables::default();
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoroshiro128StarStar;
+
+    use super::*;
+
+    type Random = Xoroshiro128StarStar;
 
-        for action in &s.actions {
-            let val = variables.get_mut(&action.name);
-            action.op.execute(val, action.value);
+    /// Loads a story, seeding the random number generator that plays it.
+    fn start(json: &str, seed: u64) -> (StoryExecutor, Random) {
+        let mut story: Story = serde_json::from_str(json).unwrap();
+        story.resolve_labels().unwrap();
+        let mut rng = Random::seed_from_u64(seed);
+        let executor = StoryExecutor::new(story, &mut rng);
+        (executor, rng)
+    }
+
+    /// Plays a story to its end, choosing the first answer offered by every prompt, and returns
+    /// the requests of the prompts that were presented.
+    fn play(json: &str, seed: u64) -> Vec<String> {
+        let (mut executor, mut rng) = start(json, seed);
+        let mut requests = Vec::new();
+        while executor.get_current_prompt().is_some() && requests.len() < 100 {
+            requests.push(executor.current_request().to_owned());
+            let choice = executor.visible_answers().first().copied();
+            executor.select_answer(choice, Timing::default(), &mut rng);
         }
+        requests
+    }
 
-        Self {
-            story: s,
-            current_batch: 0,
-            current_prompt: 0,
-            variables: Variables::default(),
+    /// Answers the first prompt of a story with the given timing, and returns the request of the
+    /// next prompt.
+    fn answer(json: &str, timing: Timing) -> String {
+        let (mut executor, mut rng) = start(json, 0);
+        executor.select_answer(Some(0), timing, &mut rng).unwrap();
+        executor.current_request().to_owned()
+    }
+
+    const STORY: &str = r#"{
+        "actions": [],
+        "triggers": [
+            { "if": { "name": "_hesitations", "op": "more-or-equal", "value": 2 }, "then": [{ "goto": "hesitant" }] },
+            { "if": { "name": "_reaction_time", "op": "more", "value": 8 }, "then": [{ "goto": "slow" }] }
+        ],
+        "batches": [{
+            "prompts": [
+                { "request": "first", "answers": [{ "text": "yes" }] },
+                { "request": "next", "answers": [{ "text": "yes" }] },
+                { "id": "hesitant", "request": "hesitant", "answers": [{ "text": "yes" }] },
+                { "id": "slow", "request": "slow", "answers": [{ "text": "yes" }] }
+            ]
+        }]
+    }"#;
+
+    #[test]
+    fn timing_fires_triggers() {
+        let timing = |reaction_time, hesitations| Timing {
+            remaining: 10.0 - reaction_time,
+            reaction_time,
+            hesitations,
+        };
+        assert_eq!(answer(STORY, timing(5.0, 0)), "next");
+        assert_eq!(answer(STORY, timing(5.0, 3)), "hesitant");
+        assert_eq!(answer(STORY, timing(9.5, 1)), "slow");
+    }
+
+    #[test]
+    fn hidden_answers_time_out() {
+        let json = r#"{
+            "actions": [],
+            "batches": [{
+                "prompts": [
+                    {
+                        "request": "first",
+                        "answers": [
+                            { "if": { "name": "shown", "op": "equal", "value": 1 }, "text": "hidden", "goto": "end" },
+                            { "text": "shown" }
+                        ]
+                    },
+                    { "request": "next", "answers": [] },
+                    { "id": "end", "request": "end", "answers": [] }
+                ]
+            }]
+        }"#;
+
+        for choice in [0, 2, 9] {
+            let (mut executor, mut rng) = start(json, 0);
+            executor.select_answer(Some(choice), Timing::default(), &mut rng);
+            assert_eq!(executor.current_request(), "next");
+            assert_eq!(executor.history()[0].answer, None);
+            assert_eq!(executor.variables().get("_timeouts"), Ok(&Value::Int(1)));
         }
     }
+
+    #[test]
+    fn calls_return() {
+        let json = r#"{
+            "actions": [],
+            "batches": [
+                { "prompts": [
+                    { "request": "p1", "answers": [{ "text": "go", "call": "sub" }] },
+                    { "request": "p2", "answers": [] }
+                ] },
+                { "id": "sub", "callable": true, "prompts": [
+                    { "request": "s1", "answers": [] },
+                    { "request": "s2", "answers": [] }
+                ] },
+                { "prompts": [{ "request": "last", "answers": [] }] }
+            ]
+        }"#;
+        assert_eq!(play(json, 0), ["p1", "s1", "s2", "p2", "last"]);
+    }
+
+    #[test]
+    fn calls_return_into_queued_batches() {
+        let json = r#"{
+            "actions": [],
+            "triggers": [{ "if": { "name": "n", "op": "equal", "value": 1 }, "then": [{ "queue": "side" }] }],
+            "batches": [
+                { "prompts": [
+                    { "request": "p1", "answers": [{ "text": "go", "actions": [{ "name": "n", "op": "set", "value": 1 }] }] },
+                    { "request": "p2", "answers": [] }
+                ] },
+                { "id": "side", "callable": true, "prompts": [
+                    { "request": "q1", "answers": [{ "text": "go", "call": "sub" }] },
+                    { "request": "q2", "answers": [] }
+                ] },
+                { "id": "sub", "callable": true, "prompts": [{ "request": "s1", "answers": [] }] }
+            ]
+        }"#;
+        assert_eq!(play(json, 0), ["p1", "q1", "s1", "q2", "p2"]);
+    }
+
+    #[test]
+    fn jumps_leave_calls() {
+        let json = r#"{
+            "actions": [],
+            "batches": [
+                { "prompts": [
+                    { "request": "a1", "answers": [{ "text": "go", "call": "sub" }] },
+                    { "request": "a2", "answers": [] }
+                ] },
+                { "id": "sub", "callable": true, "prompts": [
+                    { "request": "s1", "answers": [{ "text": "go", "goto": "last" }] }
+                ] },
+                { "id": "last", "prompts": [
+                    { "request": "l1", "answers": [] },
+                    { "request": "l2", "answers": [] }
+                ] }
+            ]
+        }"#;
+        assert_eq!(play(json, 0), ["a1", "s1", "l1", "l2"]);
+    }
+
+    #[test]
+    fn gotos_follow_labels() {
+        let json = r#"{
+            "actions": [],
+            "batches": [
+                { "prompts": [
+                    { "request": "p1", "answers": [{ "text": "go", "goto": "target" }] },
+                    { "request": "p2", "answers": [] }
+                ] },
+                { "id": "skipped", "prompts": [{ "request": "q1", "answers": [] }] },
+                { "prompts": [
+                    { "id": "target", "request": "r1", "answers": [] },
+                    { "request": "r2", "once": true, "answers": [], "on_timeout": { "goto": "skipped" } },
+                    { "request": "r3", "answers": [] }
+                ] }
+            ]
+        }"#;
+        assert_eq!(play(json, 0), ["p1", "r1", "r2", "q1", "r1", "r3"]);
+    }
+
+    #[test]
+    fn batch_conditions() {
+        let json = r#"{
+            "actions": [],
+            "batches": [
+                { "if": { "name": "n", "op": "equal", "value": 1 }, "prompts": [{ "request": "skipped", "answers": [] }] },
+                { "repeat_while": { "name": "n", "op": "less", "value": 3 }, "prompts": [
+                    { "request": "loop", "answers": [{ "text": "go", "actions": [{ "name": "n", "op": "add", "value": 1 }] }] }
+                ] },
+                { "repeat_while": { "name": "n", "op": "less", "value": 100 }, "prompts": [
+                    { "if": { "name": "n", "op": "equal", "value": 99 }, "request": "never", "answers": [] }
+                ] },
+                { "if": { "name": "n", "op": "equal", "value": 3 }, "prompts": [{ "request": "done", "answers": [] }] }
+            ]
+        }"#;
+        // A batch that presents nothing is not repeated, even if its condition holds.
+        assert_eq!(play(json, 0), ["loop", "loop", "loop", "done"]);
+    }
+
+    #[test]
+    fn draws() {
+        let json = r#"{
+            "actions": [],
+            "batches": [{
+                "random": true,
+                "draw": 2,
+                "prompts": [
+                    { "request": "a", "answers": [] },
+                    { "request": "b", "answers": [] },
+                    { "request": "c", "answers": [] },
+                    { "request": "weightless", "weight": 0, "answers": [] },
+                    { "if": { "name": "n", "op": "equal", "value": 1 }, "request": "hidden", "answers": [] }
+                ]
+            }]
+        }"#;
+
+        let mut drawn = HashSet::new();
+        for seed in 0..32 {
+            let requests = play(json, seed);
+            assert_eq!(requests.len(), 2);
+            assert_ne!(requests[0], requests[1]);
+            assert_eq!(requests, play(json, seed));
+            drawn.extend(requests);
+        }
+        let expected: HashSet<String> = ["a", "b", "c"].map(String::from).into();
+        assert_eq!(drawn, expected);
+    }
+
+    #[test]
+    fn draws_follow_weights() {
+        let json = r#"{
+            "actions": [],
+            "batches": [{
+                "random": true,
+                "draw": 1,
+                "prompts": [
+                    { "request": "light", "answers": [] },
+                    { "request": "heavy", "weight": 20, "answers": [] }
+                ]
+            }]
+        }"#;
+
+        let heavy = (0..64)
+            .filter(|&seed| play(json, seed) == ["heavy"])
+            .count();
+        assert!(heavy > 48, "heavy drawn {heavy} times out of 64");
+        assert!(heavy < 64, "light never drawn");
+    }
+
+    #[test]
+    fn groups_and_once() {
+        let json = r#"{
+            "actions": [],
+            "batches": [
+                { "random": true, "prompts": [
+                    { "request": "ast1", "group": "asteroid", "answers": [] },
+                    { "request": "ast2", "group": "asteroid", "answers": [] },
+                    { "request": "hull", "once": true, "answers": [] },
+                    { "request": "x", "answers": [] }
+                ] },
+                { "random": true, "prompts": [
+                    { "request": "ast3", "group": "asteroid", "answers": [] },
+                    { "request": "hull", "once": true, "answers": [] },
+                    { "request": "y", "answers": [] }
+                ] }
+            ]
+        }"#;
+
+        for seed in 0..16 {
+            let requests = play(json, seed);
+            let count = |f: fn(&str) -> bool| requests.iter().filter(|r| f(r)).count();
+            assert_eq!(count(|r| r.starts_with("ast")), 1, "{requests:?}");
+            assert_eq!(count(|r| r == "hull"), 1, "{requests:?}");
+            assert_eq!(count(|r| r == "x" || r == "y"), 2, "{requests:?}");
+        }
+    }
+
+    #[test]
+    fn events() {
+        let json = r#"{
+            "actions": [],
+            "batches": [{ "prompts": [
+                { "request": "p1", "answers": [] },
+                { "request": "p2", "answers": [] },
+                { "request": "p3", "answers": [] },
+                { "request": "p4", "answers": [] }
+            ] }],
+            "events": [
+                { "probability": 1, "cooldown": 1, "prompts": [{ "request": "storm", "answers": [] }] },
+                { "probability": 1, "if": { "name": "n", "op": "equal", "value": 1 }, "prompts": [{ "request": "never", "answers": [] }] }
+            ]
+        }"#;
+        // Events do not interrupt each other, and cannot happen again during their cooldown.
+        assert_eq!(play(json, 0), ["p1", "storm", "p2", "p3", "storm", "p4"]);
+
+        let json = json.replace(
+            r#""probability": 1, "cooldown""#,
+            r#""probability": 0.5, "cooldown""#,
+        );
+        let storms: Vec<usize> = (0..16)
+            .map(|seed| play(&json, seed).iter().filter(|r| *r == "storm").count())
+            .collect();
+        assert!(storms.contains(&0) && storms.iter().any(|&count| count > 0));
+    }
+
+    #[test]
+    fn events_can_be_called() {
+        let json = r#"{
+            "actions": [],
+            "batches": [{ "prompts": [
+                { "request": "p1", "answers": [{ "text": "call", "call": "storm" }] },
+                { "request": "p2", "answers": [] }
+            ] }],
+            "events": [{ "probability": 0, "prompts": [{ "id": "storm", "request": "storm", "answers": [] }] }]
+        }"#;
+        assert_eq!(play(json, 0), ["p1", "storm", "p2"]);
+    }
+
+    #[test]
+    fn delayed_actions() {
+        let json = r#"{
+            "actions": [],
+            "batches": [{ "prompts": [
+                { "request": "patch", "answers": [{ "text": "go", "actions": [
+                    { "name": "hull", "op": "set", "value": 1 },
+                    { "name": "hull", "op": "set", "value": 0, "delay": 2 }
+                ] }] },
+                { "request": "p2", "answers": [] },
+                { "request": "p3", "answers": [] },
+                { "request": "p4", "answers": [] }
+            ] }]
+        }"#;
+        let (mut executor, mut rng) = start(json, 0);
+        let hull = |executor: &StoryExecutor| executor.variables().get("hull").unwrap().clone();
+
+        executor.select_answer(Some(0), Timing::default(), &mut rng);
+        assert_eq!(hull(&executor), Value::Int(1));
+        assert_eq!(executor.pending_actions().len(), 1);
+        assert_eq!(executor.pending_actions()[0].remaining, 2);
+        let state = serde_json::to_value(executor.pending_actions()).unwrap();
+        assert_eq!(state[0]["remaining"], 2);
+        assert_eq!(state[0]["action"]["name"], "hull");
+
+        executor.select_answer(None, Timing::default(), &mut rng);
+        assert_eq!(hull(&executor), Value::Int(1));
+        assert_eq!(executor.pending_actions()[0].remaining, 1);
+
+        executor.select_answer(None, Timing::default(), &mut rng);
+        assert_eq!(hull(&executor), Value::Int(0));
+        assert!(executor.pending_actions().is_empty());
+
+        executor.restart(&mut rng);
+        assert_eq!(hull(&executor), Value::Int(0));
+        assert!(executor.pending_actions().is_empty());
+    }
+
+    #[test]
+    fn outcomes() {
+        let json = r#"{
+            "actions": [],
+            "batches": [
+                { "prompts": [
+                    { "request": "dodge", "answers": [{
+                        "text": "go",
+                        "actions": [{ "name": "tries", "op": "add", "value": 1 }],
+                        "outcomes": [
+                            { "text": "Hit!", "actions": [{ "name": "hull", "op": "sub", "value": 1 }], "goto": "repair" },
+                            { "text": "Dodged.", "actions": [{ "name": "hull", "op": "add", "value": 1 }] },
+                            { "weight": 0, "text": "never" }
+                        ]
+                    }] },
+                    { "request": "next", "answers": [] }
+                ] },
+                { "prompts": [{ "id": "repair", "request": "repair", "answers": [] }] }
+            ]
+        }"#;
+
+        let mut follow_ups = HashSet::new();
+        for seed in 0..16 {
+            let (mut executor, mut rng) = start(json, seed);
+            executor.select_answer(Some(0), Timing::default(), &mut rng);
+            let follow_up = executor.take_follow_up().unwrap();
+            assert_eq!(executor.take_follow_up(), None);
+
+            let variables = executor.variables();
+            assert_eq!(variables.get("tries"), Ok(&Value::Int(1)));
+            let (hull, request) = match follow_up.as_str() {
+                "Hit!" => (-1, "repair"),
+                "Dodged." => (1, "next"),
+                other => panic!("unexpected outcome {other}"),
+            };
+            assert_eq!(variables.get("hull"), Ok(&Value::Int(hull)));
+            assert_eq!(executor.current_request(), request);
+
+            // The same seed rolls the same outcome.
+            let (mut replay, mut rng) = start(json, seed);
+            replay.select_answer(Some(0), Timing::default(), &mut rng);
+            assert_eq!(replay.take_follow_up(), Some(follow_up.clone()));
+            follow_ups.insert(follow_up);
+        }
+        assert_eq!(follow_ups.len(), 2);
+    }
+
+    #[test]
+    fn triggers() {
+        let json = r#"{
+            "actions": [],
+            "triggers": [
+                { "if": { "name": "lost", "op": "more", "value": 5 }, "then": [{ "queue": "mourning" }, { "play": "SFX/trucquisecasse.wav" }] },
+                { "if": { "name": "lost", "op": "more", "value": 5 }, "then": [{ "play": "SFX/alarm.wav" }], "once": true }
+            ],
+            "batches": [
+                { "prompts": [
+                    { "request": "p1", "answers": [{ "text": "go", "actions": [{ "name": "lost", "op": "set", "value": 6 }] }] },
+                    { "request": "p2", "answers": [{ "text": "go", "actions": [{ "name": "lost", "op": "set", "value": 7 }] }] },
+                    { "request": "p3", "answers": [{ "text": "go", "actions": [{ "name": "lost", "op": "set", "value": 0 }] }] },
+                    { "request": "p4", "answers": [{ "text": "go", "actions": [{ "name": "lost", "op": "set", "value": 9 }] }] },
+                    { "request": "p5", "answers": [] }
+                ] },
+                { "id": "mourning", "callable": true, "prompts": [{ "request": "mourning", "answers": [] }] }
+            ]
+        }"#;
+
+        let (mut executor, mut rng) = start(json, 0);
+        let mut requests = vec![executor.current_request().to_owned()];
+        let mut sounds = Vec::new();
+        while executor.get_current_prompt().is_some() {
+            let choice = executor.visible_answers().first().copied();
+            executor.select_answer(choice, Timing::default(), &mut rng);
+            sounds.push(executor.drain_sounds().collect::<Vec<_>>());
+            if executor.get_current_prompt().is_some() {
+                requests.push(executor.current_request().to_owned());
+            }
+        }
+
+        // The triggers fire when `lost` crosses 5, not while it stays above.
+        assert_eq!(
+            requests,
+            ["p1", "mourning", "p2", "p3", "p4", "mourning", "p5"]
+        );
+        assert_eq!(
+            sounds[0],
+            ["SFX/trucquisecasse.wav", "SFX/alarm.wav"].map(String::from)
+        );
+        assert!(sounds[2].is_empty() && sounds[3].is_empty());
+        // The trigger that only fires once stays silent the second time.
+        assert_eq!(sounds[4], ["SFX/trucquisecasse.wav"].map(String::from));
+    }
 }
@@ -0,0 +1,597 @@
+//! A small expression language used to compute values from the story's variables.
+//!
+//! Expressions are written as strings in the story file, such as
+//! `"clamp(crewmate_count - crewmate_lost, 0, 20)"`. Plain numbers and booleans are accepted as
+//! well. Strings are quoted within the expression: `"'engineer'"`.
+//!
+//! Variable names may contain dashes (`no-map`), so the subtraction operator must be surrounded
+//! by spaces: `x-1` is a variable, and `x -1` is an error.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+
+use super::{EvalError, Type, Value, Variables};
+
+/// A binary operator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinOp {
+    /// Applies the operator. Integers stay integers unless mixed with floats, and strings can be
+    /// concatenated. Dividing by zero yields zero.
+    pub fn apply(self, lhs: Value, rhs: Value) -> Result<Value, EvalError> {
+        match (&lhs, &rhs) {
+            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(match self {
+                Self::Add => a.wrapping_add(*b),
+                Self::Sub => a.wrapping_sub(*b),
+                Self::Mul => a.wrapping_mul(*b),
+                Self::Div => a.checked_div(*b).unwrap_or(0),
+                Self::Rem => a.checked_rem(*b).unwrap_or(0),
+            })),
+            (Value::Text(a), Value::Text(b)) if self == Self::Add => {
+                Ok(Value::Text(format!("{a}{b}")))
+            }
+            _ => match (lhs.as_f64(), rhs.as_f64()) {
+                (Some(_), Some(b)) if b == 0.0 && matches!(self, Self::Div | Self::Rem) => {
+                    Ok(Value::Float(0.0))
+                }
+                (Some(a), Some(b)) => Ok(Value::Float(match self {
+                    Self::Add => a + b,
+                    Self::Sub => a - b,
+                    Self::Mul => a * b,
+                    Self::Div => a / b,
+                    Self::Rem => a % b,
+                })),
+                _ => Err(EvalError::Operands(self.symbol(), vec![lhs.ty(), rhs.ty()])),
+            },
+        }
+    }
+
+    /// The type of the result of the operator, or `None` when it does not support the types of
+    /// its operands.
+    pub fn result(self, lhs: Type, rhs: Type) -> Option<Type> {
+        match (lhs, rhs) {
+            (Type::Int, Type::Int) => Some(Type::Int),
+            (Type::String, Type::String) if self == Self::Add => Some(Type::String),
+            (Type::Int | Type::Float, Type::Int | Type::Float) => Some(Type::Float),
+            _ => None,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Rem => "%",
+        }
+    }
+}
+
+/// A built-in function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Function {
+    /// The smallest of its arguments.
+    Min,
+    /// The largest of its arguments.
+    Max,
+    /// `clamp(x, min, max)` restricts `x` to the `[min, max]` range.
+    Clamp,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "min" => Some(Self::Min),
+            "max" => Some(Self::Max),
+            "clamp" => Some(Self::Clamp),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::Clamp => "clamp",
+        }
+    }
+
+    /// Returns whether the function accepts `count` arguments.
+    fn accepts(self, count: usize) -> bool {
+        match self {
+            Self::Min | Self::Max => count >= 1,
+            Self::Clamp => count == 3,
+        }
+    }
+
+    /// Applies the function to numbers. The chosen argument keeps its type.
+    pub fn apply(self, args: &[Value]) -> Result<Value, EvalError> {
+        let smallest = |a: Value, b: Value| match b.compare(&a) {
+            Some(Ordering::Less) => b,
+            _ => a,
+        };
+        let largest = |a: Value, b: Value| match b.compare(&a) {
+            Some(Ordering::Greater) => b,
+            _ => a,
+        };
+
+        let numbers = args.iter().all(|arg| arg.as_f64().is_some());
+        match (self, args) {
+            (Self::Min, [first, rest @ ..]) if numbers => {
+                Ok(rest.iter().cloned().fold(first.clone(), smallest))
+            }
+            (Self::Max, [first, rest @ ..]) if numbers => {
+                Ok(rest.iter().cloned().fold(first.clone(), largest))
+            }
+            (Self::Clamp, [x, min, max]) if numbers => {
+                Ok(smallest(largest(x.clone(), min.clone()), max.clone()))
+            }
+            _ => Err(EvalError::Operands(
+                self.name(),
+                args.iter().map(Value::ty).collect(),
+            )),
+        }
+    }
+}
+
+/// An expression that evaluates to a [`Value`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    /// A literal value.
+    Literal(Value),
+    /// The value of a variable.
+    Variable(String),
+    /// The opposite of an expression.
+    Neg(Box<Expr>),
+    /// A binary operation.
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    /// A call to a built-in function.
+    Call(Function, Vec<Expr>),
+}
+
+impl Expr {
+    /// Adds the names of the variables used by this expression to `names`.
+    pub fn variables<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Self::Literal(_) => {}
+            Self::Variable(name) => names.push(name),
+            Self::Neg(expr) => expr.variables(names),
+            Self::Binary(_, lhs, rhs) => {
+                lhs.variables(names);
+                rhs.variables(names);
+            }
+            Self::Call(_, args) => args.iter().for_each(|arg| arg.variables(names)),
+        }
+    }
+
+    /// Infers the type of the value of the expression, given the types of the variables. Returns
+    /// `None` when it cannot be known before the expression is evaluated.
+    pub fn ty(&self, variables: &impl Fn(&str) -> Option<Type>) -> Option<Type> {
+        match self {
+            Self::Literal(value) => Some(value.ty()),
+            Self::Variable(name) => variables(name),
+            Self::Neg(expr) => expr
+                .ty(variables)
+                .filter(|ty| matches!(ty, Type::Int | Type::Float)),
+            Self::Binary(op, lhs, rhs) => op.result(lhs.ty(variables)?, rhs.ty(variables)?),
+            // The chosen argument keeps its type, which is only known if they all share it.
+            Self::Call(_, args) => {
+                let first = args.first()?.ty(variables)?;
+                args.iter()
+                    .all(|arg| arg.ty(variables) == Some(first))
+                    .then_some(first)
+            }
+        }
+    }
+
+    /// Evaluates the expression against the provided variables.
+    pub fn eval(&self, variables: &Variables) -> Result<Value, EvalError> {
+        match self {
+            Self::Literal(value) => Ok(value.clone()),
+            Self::Variable(name) => variables.get(name).cloned(),
+            Self::Neg(expr) => match expr.eval(variables)? {
+                Value::Int(value) => Ok(Value::Int(value.wrapping_neg())),
+                Value::Float(value) => Ok(Value::Float(-value)),
+                value => Err(EvalError::Operands("-", vec![value.ty()])),
+            },
+            Self::Binary(op, lhs, rhs) => op.apply(lhs.eval(variables)?, rhs.eval(variables)?),
+            Self::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(variables))
+                    .collect::<Result<Vec<_>, _>>()?;
+                function.apply(&args)
+            }
+        }
+    }
+}
+
+/// An error that occured while parsing an [`Expr`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// The byte offset at which the error occured.
+    pub position: usize,
+    /// What went wrong.
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at column {})", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A recursive descent parser for [`Expr`].
+struct Parser<'a> {
+    source: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            position: self.position,
+            message: message.into(),
+        })
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.source[self.position..];
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    /// Returns the next non-whitespace character without consuming it.
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.source[self.position..].chars().next()
+    }
+
+    /// Consumes `c` if it is the next non-whitespace character.
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.position += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            match self.peek() {
+                Some(found) => self.error(format!("expected `{c}`, found `{found}`")),
+                None => self.error(format!("expected `{c}`, found the end of the expression")),
+            }
+        }
+    }
+
+    /// Consumes a variable or function name. Dashes are part of the name when they are followed
+    /// by another character of the name.
+    fn take_name(&mut self) -> &'a str {
+        let word = |c: char| c.is_alphanumeric() || c == '_';
+        let start = self.position;
+        loop {
+            self.take_while(word);
+            match self.source[self.position..].strip_prefix('-') {
+                Some(rest) if rest.starts_with(word) => self.position += 1,
+                _ => return &self.source[start..self.position],
+            }
+        }
+    }
+
+    /// Consumes the longest prefix whose characters match `pred`.
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let rest = &self.source[self.position..];
+        let len = rest.find(|c| !pred(c)).unwrap_or(rest.len());
+        self.position += len;
+        &rest[..len]
+    }
+
+    /// expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = if self.eat('+') {
+                BinOp::Add
+            } else if self.peek() == Some('-') {
+                // Variable names may contain dashes, so `a-b` would be ambiguous.
+                let spaced = self.source[..self.position].ends_with(char::is_whitespace)
+                    && self.source[self.position + 1..].starts_with(char::is_whitespace);
+                if !spaced {
+                    return self.error("`-` must be surrounded by spaces to subtract");
+                }
+                self.position += 1;
+                BinOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.term()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    /// term := unary (('*' | '/' | '%') unary)*
+    fn term(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                BinOp::Mul
+            } else if self.eat('/') {
+                BinOp::Div
+            } else if self.eat('%') {
+                BinOp::Rem
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.unary()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    /// unary := '-' unary | atom
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat('-') {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else {
+            self.atom()
+        }
+    }
+
+    /// atom := number | string | name | name '(' expr (',' expr)* ')' | '(' expr ')'
+    fn atom(&mut self) -> Result<Expr, ParseError> {
+        match self.peek() {
+            Some('(') => {
+                self.position += 1;
+                let expr = self.expr()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some(quote @ ('\'' | '"')) => {
+                let start = self.position;
+                self.position += 1;
+                let text = self.take_while(|c| c != quote);
+                if !self.eat(quote) {
+                    self.position = start;
+                    return self.error("unterminated string");
+                }
+                Ok(Expr::Literal(Value::Text(text.to_owned())))
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let start = self.position;
+                let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+                let value = if number.contains('.') {
+                    number.parse().ok().map(Value::Float)
+                } else {
+                    number.parse().ok().map(Value::Int)
+                };
+                match value {
+                    Some(value) => Ok(Expr::Literal(value)),
+                    None => {
+                        self.position = start;
+                        self.error(format!("invalid number `{number}`"))
+                    }
+                }
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let start = self.position;
+                let name = self.take_name();
+                if self.peek() != Some('(') {
+                    return Ok(match name {
+                        "true" => Expr::Literal(Value::Bool(true)),
+                        "false" => Expr::Literal(Value::Bool(false)),
+                        _ => Expr::Variable(name.to_owned()),
+                    });
+                }
+
+                let function = match Function::from_name(name) {
+                    Some(function) => function,
+                    None => {
+                        self.position = start;
+                        return self.error(format!("unknown function `{name}`"));
+                    }
+                };
+
+                self.position += 1;
+                let mut args = vec![self.expr()?];
+                while self.eat(',') {
+                    args.push(self.expr()?);
+                }
+                self.expect(')')?;
+
+                if !function.accepts(args.len()) {
+                    self.position = start;
+                    return self.error(format!(
+                        "`{}` does not accept {} argument(s)",
+                        function.name(),
+                        args.len()
+                    ));
+                }
+
+                Ok(Expr::Call(function, args))
+            }
+            Some(c) => self.error(format!("unexpected `{c}`")),
+            None => self.error("unexpected end of the expression"),
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = ParseError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            source,
+            position: 0,
+        };
+        let expr = parser.expr()?;
+        match parser.peek() {
+            Some(c) => parser.error(format!("unexpected `{c}`")),
+            None => Ok(expr),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        /// Writes a sub-expression, wrapping binary operations in parentheses.
+        fn operand(f: &mut fmt::Formatter<'_>, expr: &Expr) -> fmt::Result {
+            match expr {
+                Expr::Binary(..) => write!(f, "({expr})"),
+                _ => write!(f, "{expr}"),
+            }
+        }
+
+        match self {
+            Self::Literal(Value::Text(text)) => write!(f, "'{text}'"),
+            Self::Literal(value) => write!(f, "{value}"),
+            Self::Variable(name) => f.write_str(name),
+            Self::Neg(expr) => {
+                f.write_str("-")?;
+                operand(f, expr)
+            }
+            Self::Binary(op, lhs, rhs) => {
+                operand(f, lhs)?;
+                write!(f, " {} ", op.symbol())?;
+                operand(f, rhs)
+            }
+            Self::Call(function, args) => {
+                write!(f, "{}(", function.name())?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{arg}")?;
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+impl Serialize for Expr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Literal(Value::Text(_)) => serializer.collect_str(self),
+            Self::Literal(value) => value.serialize(serializer),
+            _ => serializer.collect_str(self),
+        }
+    }
+}
+
+/// Accepts either a number or a string to be parsed.
+struct ExprVisitor;
+
+impl<'de> Visitor<'de> for ExprVisitor {
+    type Value = Expr;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a number, a boolean or an expression")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Expr, E> {
+        Ok(Expr::Literal(Value::Bool(v)))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Expr, E> {
+        Ok(Expr::Literal(Value::Int(v)))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Expr, E> {
+        i64::try_from(v)
+            .map(|v| Expr::Literal(Value::Int(v)))
+            .map_err(|_| E::custom(format!("`{v}` is too large")))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Expr, E> {
+        Ok(Expr::Literal(Value::Float(v)))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Expr, E> {
+        v.parse()
+            .map_err(|err| E::custom(format!("invalid expression `{v}`: {err}")))
+    }
+}
+
+impl<'de> Deserialize<'de> for Expr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ExprVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> Value {
+        let mut variables = Variables::default();
+        variables.set("x", Value::Int(4)).unwrap();
+        variables.set("no-map", Value::Int(1)).unwrap();
+        let expr: Expr = source.parse().unwrap();
+        expr.eval(&variables).unwrap()
+    }
+
+    fn error(source: &str) -> ParseError {
+        source.parse::<Expr>().unwrap_err()
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Value::Int(7));
+        assert_eq!(eval("(1 + 2) * 3"), Value::Int(9));
+        assert_eq!(eval("10 - 4 - 3"), Value::Int(3));
+        assert_eq!(eval("7 % 4 * 2"), Value::Int(6));
+        assert_eq!(eval("1 + 1.5"), Value::Float(2.5));
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_eq!(eval("-2 * 3"), Value::Int(-6));
+        assert_eq!(eval("2 * -3"), Value::Int(-6));
+        assert_eq!(eval("- -x"), Value::Int(4));
+        assert_eq!(eval("1 - -x"), Value::Int(5));
+    }
+
+    #[test]
+    fn dashes() {
+        assert_eq!(eval("no-map - 1"), Value::Int(0));
+        assert_eq!(eval("x-1"), Value::Int(0));
+        assert_eq!(error("x -1").position, 2);
+        assert_eq!(error("3-2").position, 1);
+        assert_eq!(error("x- 1").position, 1);
+    }
+
+    #[test]
+    fn calls() {
+        assert_eq!(eval("min(3, x, 2)"), Value::Int(2));
+        assert_eq!(eval("max(x, 2.5)"), Value::Int(4));
+        assert_eq!(eval("clamp(x * 3, 0, 10)"), Value::Int(10));
+        assert_eq!(eval("'a' + 'b'"), Value::Text("ab".into()));
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(error("1 +").position, 3);
+        assert_eq!(error("1 + )").position, 4);
+        assert_eq!(error("(1 + 2").position, 6);
+        assert_eq!(error("clamp(1, 2)").position, 0);
+        assert_eq!(error("sqrt(4)").position, 0);
+        assert_eq!(error("'open").position, 0);
+        assert_eq!(error("1 2").position, 2);
+    }
+}
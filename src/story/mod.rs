@@ -1,5 +1,8 @@
 //! Defines the structures and logic required to parse the main story .JSON file.
 
+mod expr;
+pub use self::expr::*;
+
 mod model;
 pub use self::model::*;
 
@@ -8,3 +11,6 @@ pub use self::variables::*;
 
 mod executor;
 pub use self::executor::*;
+
+mod text;
+pub use self::text::*;
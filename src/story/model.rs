@@ -1,9 +1,21 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+#[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
 use std::fs::File;
 use std::io;
+#[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
 use std::io::BufReader;
 
+use rand::{Rng, RngCore};
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
 
+use super::{
+    BinOp, Declaration, EvalError, Expr, Function, ParseError, Template, TextError, Type, Value,
+    Variables,
+};
+
 /// A function that may be executed on a variable.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
@@ -14,17 +26,88 @@ pub enum Operation {
     Add,
     /// Subtracts a specific value from the variable.
     Sub,
+    /// Multiplies the variable by a specific value.
+    Mul,
+    /// Divides the variable by a specific value. Dividing by zero yields zero, as in expressions.
+    Div,
+    /// Sets the variable to the smallest of itself and a specific value.
+    Min,
+    /// Sets the variable to the largest of itself and a specific value.
+    Max,
+    /// Restricts the variable to an inclusive `[min, max]` range.
+    Clamp,
+    /// Sets the variable to a random value in an inclusive `[min, max]` range.
+    Random,
 }
 
 impl Operation {
-    /// Executes the operation on the provided value
-    pub fn execute(self, value: &mut i64, other: i64) {
+    /// Executes the operation on the provided value.
+    ///
+    /// `clamp` and `random` expect exactly two values, other operations expect a single one;
+    /// stories are checked for this when they are loaded.
+    pub fn execute(
+        self,
+        value: &mut Value,
+        other: &[Value],
+        rng: &mut dyn RngCore,
+    ) -> Result<(), EvalError> {
+        let current = value.clone();
+        *value = match (self, other) {
+            (Self::Set, [other]) => other.clone(),
+            (Self::Add, [other]) => BinOp::Add.apply(current, other.clone())?,
+            (Self::Sub, [other]) => BinOp::Sub.apply(current, other.clone())?,
+            (Self::Mul, [other]) => BinOp::Mul.apply(current, other.clone())?,
+            (Self::Div, [other]) => BinOp::Div.apply(current, other.clone())?,
+            (Self::Min, [other]) => Function::Min.apply(&[current, other.clone()])?,
+            (Self::Max, [other]) => Function::Max.apply(&[current, other.clone()])?,
+            (Self::Clamp, [min, max]) => {
+                Function::Clamp.apply(&[current, min.clone(), max.clone()])?
+            }
+            (Self::Random, [Value::Int(min), Value::Int(max)]) if min <= max => {
+                Value::Int(rng.gen_range(*min..=*max))
+            }
+            (Self::Random, [min, max]) => match (min.as_f64(), max.as_f64()) {
+                (Some(min), Some(max)) if min <= max => Value::Float(rng.gen_range(min..=max)),
+                _ => return Err(self.invalid(other)),
+            },
+            _ => return Err(self.invalid(other)),
+        };
+        Ok(())
+    }
+
+    /// The name of the operation in the story file.
+    fn name(self) -> &'static str {
         match self {
-            Self::Set => *value = other,
-            Self::Add => *value += other,
-            Self::Sub => *value -= other,
+            Self::Set => "set",
+            Self::Add => "add",
+            Self::Sub => "sub",
+            Self::Mul => "mul",
+            Self::Div => "div",
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::Clamp => "clamp",
+            Self::Random => "random",
+        }
+    }
+
+    /// Checks that `operand` has as many values as the operation expects: two for `clamp` and
+    /// `random`, and a single one otherwise.
+    pub fn check_operand(self, operand: &Operand) -> Result<(), String> {
+        match (self, operand.count()) {
+            (Self::Clamp | Self::Random, 2) => Ok(()),
+            (Self::Clamp | Self::Random, _) => Err(format!(
+                "`{}` expects two values, such as `[0, 10]`",
+                self.name()
+            )),
+            (_, 1) => Ok(()),
+            _ => Err(format!("`{}` expects a single value", self.name())),
         }
     }
+
+    /// The error returned when the operation is given invalid parameters.
+    fn invalid(self, other: &[Value]) -> EvalError {
+        EvalError::Operands(self.name(), other.iter().map(Value::ty).collect())
+    }
 }
 
 /// A comparaison function.
@@ -39,98 +122,1141 @@ pub enum Compare {
     Less,
     /// The variable must be greater than a specfic value.
     More,
+    /// The variable must be less than or equal to a specific value.
+    LessOrEqual,
+    /// The variable must be greater than or equal to a specific value.
+    MoreOrEqual,
+    /// The variable must be within an inclusive `[min, max]` range.
+    Between,
+    /// The variable must equal one of the provided values.
+    OneOf,
 }
 
 impl Compare {
+    /// The name of the comparison in the story file.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Equal => "equal",
+            Self::Not => "not",
+            Self::Less => "less",
+            Self::More => "more",
+            Self::LessOrEqual => "less-or-equal",
+            Self::MoreOrEqual => "more-or-equal",
+            Self::Between => "between",
+            Self::OneOf => "one-of",
+        }
+    }
+
+    /// Checks that `operand` has as many values as the comparison expects: two for `between`, at
+    /// least one for `one-of`, and a single one otherwise.
+    pub fn check_operand(self, operand: &Operand) -> Result<(), String> {
+        match (self, operand.count()) {
+            (Self::Between, 2) => Ok(()),
+            (Self::Between, _) => Err("`between` expects two values, such as `[1, 5]`".into()),
+            (Self::OneOf, 0) => Err("`one-of` expects at least one value".into()),
+            (Self::OneOf, _) | (_, 1) => Ok(()),
+            _ => Err(format!("`{}` expects a single value", self.name())),
+        }
+    }
+
     /// Determines whether `value` `op` `other`.
-    pub fn check(self, value: i64, other: i64) -> bool {
+    ///
+    /// Comparisons against a single value fail when given a list, and `between` expects exactly
+    /// two values; stories are checked for this when they are loaded.
+    /// Values that cannot be compared, such as a string and a number, are never equal.
+    pub fn check(self, value: &Value, other: &[Value]) -> bool {
+        use Ordering::*;
+
+        let cmp = |other: &Value| value.compare(other);
+        match (self, other) {
+            (Self::Equal, [other]) => cmp(other) == Some(Equal),
+            (Self::Not, [other]) => cmp(other) != Some(Equal),
+            (Self::Less, [other]) => cmp(other) == Some(Less),
+            (Self::More, [other]) => cmp(other) == Some(Greater),
+            (Self::LessOrEqual, [other]) => matches!(cmp(other), Some(Less | Equal)),
+            (Self::MoreOrEqual, [other]) => matches!(cmp(other), Some(Greater | Equal)),
+            (Self::Between, [min, max]) => {
+                matches!(cmp(min), Some(Greater | Equal)) && matches!(cmp(max), Some(Less | Equal))
+            }
+            (Self::OneOf, others) => others.iter().any(|other| cmp(other) == Some(Equal)),
+            _ => false,
+        }
+    }
+}
+
+/// The parameter(s) of an [`Operation`] or a [`Compare`] function.
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Operand {
+    Single(Expr),
+    List(Vec<Expr>),
+}
+
+impl Operand {
+    /// The number of values of this operand.
+    pub fn count(&self) -> usize {
         match self {
-            Self::Equal => value == other,
-            Self::Not => value != other,
-            Self::Less => value < other,
-            Self::More => value > other,
+            Self::Single(_) => 1,
+            Self::List(exprs) => exprs.len(),
+        }
+    }
+
+    /// Adds the names of the variables used by this operand to `names`.
+    pub fn variables<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Self::Single(expr) => expr.variables(names),
+            Self::List(exprs) => exprs.iter().for_each(|expr| expr.variables(names)),
+        }
+    }
+
+    /// Evaluates the values of this operand.
+    pub fn eval(&self, variables: &Variables) -> Result<Vec<Value>, EvalError> {
+        match self {
+            Self::Single(expr) => Ok(vec![expr.eval(variables)?]),
+            Self::List(exprs) => exprs.iter().map(|expr| expr.eval(variables)).collect(),
         }
     }
 }
 
-/// A pre-condition for a specific [`Prompt`].
+/// Accepts either a single expression or a list of expressions.
+struct OperandVisitor;
+
+impl<'de> Visitor<'de> for OperandVisitor {
+    type Value = Operand;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an expression or a list of expressions")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Operand, E> {
+        Ok(Operand::Single(Expr::Literal(Value::Bool(v))))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Operand, E> {
+        Ok(Operand::Single(Expr::Literal(Value::Int(v))))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Operand, E> {
+        Expr::deserialize(de::value::U64Deserializer::new(v)).map(Operand::Single)
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Operand, E> {
+        Ok(Operand::Single(Expr::Literal(Value::Float(v))))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Operand, E> {
+        Expr::deserialize(de::value::StrDeserializer::new(v)).map(Operand::Single)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Operand, A::Error> {
+        let mut exprs = Vec::new();
+        while let Some(expr) = seq.next_element()? {
+            exprs.push(expr);
+        }
+        Ok(Operand::List(exprs))
+    }
+}
+
+impl<'de> Deserialize<'de> for Operand {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(OperandVisitor)
+    }
+}
+
+/// Compares a variable against some value(s).
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Condition {
+pub struct Comparison {
     /// The name of the variable that's being checked.
     pub name: String,
     /// The comparaison function.
     pub op: Compare,
     /// The value against which the variable is beging checked.
-    pub value: i64,
+    pub value: Operand,
+}
+
+/// A condition over the story's variables.
+///
+/// Conditions can be combined with `all`, `any` and `not`. A single comparison is written as-is.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged, try_from = "RawCondition")]
+pub enum Condition {
+    /// Every condition must hold.
+    All { all: Vec<Condition> },
+    /// At least one condition must hold.
+    Any { any: Vec<Condition> },
+    /// The condition must not hold.
+    Not { not: Box<Condition> },
+    /// A single comparison must hold.
+    Compare(Comparison),
+}
+
+impl Condition {
+    /// Adds the comparisons of this condition, including the nested ones, to `comparisons`.
+    pub fn comparisons<'a>(&'a self, comparisons: &mut Vec<&'a Comparison>) {
+        match self {
+            Self::All { all: conditions } | Self::Any { any: conditions } => conditions
+                .iter()
+                .for_each(|condition| condition.comparisons(comparisons)),
+            Self::Not { not } => not.comparisons(comparisons),
+            Self::Compare(c) => comparisons.push(c),
+        }
+    }
+
+    /// Adds the names of the variables used by this condition to `names`.
+    pub fn variables<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Self::All { all: conditions } | Self::Any { any: conditions } => conditions
+                .iter()
+                .for_each(|condition| condition.variables(names)),
+            Self::Not { not } => not.variables(names),
+            Self::Compare(c) => {
+                names.push(&c.name);
+                c.value.variables(names);
+            }
+        }
+    }
+
+    /// Determines whether this condition holds for the provided variables.
+    pub fn check(&self, variables: &Variables) -> Result<bool, EvalError> {
+        match self {
+            Self::All { all } => {
+                for condition in all {
+                    if !condition.check(variables)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Self::Any { any } => {
+                for condition in any {
+                    if condition.check(variables)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Self::Not { not } => Ok(!not.check(variables)?),
+            Self::Compare(c) => {
+                Ok(c.op.check(variables.get(&c.name)?, &c.value.eval(variables)?))
+            }
+        }
+    }
+}
+
+/// The shape of a [`Condition`] in the story file. Going through this structure rather than an
+/// untagged enum preserves the errors of nested expressions.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCondition {
+    all: Option<Vec<Condition>>,
+    any: Option<Vec<Condition>>,
+    not: Option<Box<Condition>>,
+    name: Option<String>,
+    op: Option<Compare>,
+    value: Option<Operand>,
+}
+
+impl TryFrom<RawCondition> for Condition {
+    type Error = String;
+
+    fn try_from(raw: RawCondition) -> Result<Self, Self::Error> {
+        match raw {
+            RawCondition {
+                all: Some(all),
+                any: None,
+                not: None,
+                name: None,
+                op: None,
+                value: None,
+            } => Ok(Self::All { all }),
+            RawCondition {
+                all: None,
+                any: Some(any),
+                not: None,
+                name: None,
+                op: None,
+                value: None,
+            } => Ok(Self::Any { any }),
+            RawCondition {
+                all: None,
+                any: None,
+                not: Some(not),
+                name: None,
+                op: None,
+                value: None,
+            } => Ok(Self::Not { not }),
+            RawCondition {
+                all: None,
+                any: None,
+                not: None,
+                name: Some(name),
+                op: Some(op),
+                value: Some(value),
+            } => {
+                op.check_operand(&value)?;
+                Ok(Self::Compare(Comparison { name, op, value }))
+            }
+            _ => Err(
+                "a condition is either `all`, `any`, `not`, or a `name`, `op` and `value`".into(),
+            ),
+        }
+    }
 }
 
 /// An action that may be taken when the player chooses a specific answer.
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "RawAction")]
 pub struct Action {
     /// The name of the variable that'll be modified.
     pub name: String,
     /// The operation that'll be executed.
     pub op: Operation,
-    /// The other parameter of the operation.
-    pub value: i64,
+    /// The other parameter(s) of the operation.
+    pub value: Operand,
+    /// The number of answers to wait for before the action is applied. Actions without a delay
+    /// are applied immediately.
+    #[serde(default)]
+    pub delay: usize,
+}
+
+/// The shape of an [`Action`] in the story file, before its operand is checked.
+#[derive(Deserialize)]
+struct RawAction {
+    name: String,
+    op: Operation,
+    value: Operand,
+    #[serde(default)]
+    delay: usize,
+}
+
+impl TryFrom<RawAction> for Action {
+    type Error = String;
+
+    fn try_from(raw: RawAction) -> Result<Self, Self::Error> {
+        raw.op.check_operand(&raw.value)?;
+        Ok(Self {
+            name: raw.name,
+            op: raw.op,
+            value: raw.value,
+            delay: raw.delay,
+        })
+    }
+}
+
+impl Action {
+    /// Evaluates the value of this action and applies it to the provided variables.
+    pub fn apply(&self, variables: &mut Variables, rng: &mut dyn RngCore) -> Result<(), EvalError> {
+        let other = self.value.eval(variables)?;
+        let mut value = variables.get(&self.name)?.clone();
+        self.op.execute(&mut value, &other, rng)?;
+        variables.set(&self.name, value)
+    }
 }
 
 /// An possible answer.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Answer {
+    /// A condition for this answer to be offered. If no condition is specified, the answer is
+    /// always offered.
+    #[serde(rename = "if")]
+    pub condition: Option<Condition>,
     /// The text of the answer.
     #[serde(default)]
     pub text: String,
     /// A collection of actions for this answer.
     #[serde(default)]
     pub actions: Vec<Action>,
+    /// The label of the [`Batch`] or [`Prompt`] to jump to once this answer is chosen. If no label
+    /// is specified, the story simply moves on. Jumping out of a called batch does not come back
+    /// from the call.
+    pub goto: Option<String>,
+    /// The label of a [`Batch`] or [`Prompt`] to play once this answer is chosen, before coming
+    /// back to the prompt that follows this one.
+    pub call: Option<String>,
+    /// The possible consequences of this answer, of which one is chosen at random.
+    #[serde(default)]
+    pub outcomes: Vec<Outcome>,
+}
+
+/// A possible consequence of an [`Answer`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Outcome {
+    /// How likely this outcome is, relative to the other outcomes of the answer.
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    /// A collection of actions applied after the actions of the answer.
+    #[serde(default)]
+    pub actions: Vec<Action>,
+    /// A text shown on the terminal before the next request.
+    pub text: Option<String>,
+    /// The label of the [`Batch`] or [`Prompt`] to jump to. It replaces the jump of the answer.
+    pub goto: Option<String>,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+/// What happens when the player does not answer a prompt in time.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Timeout {
+    /// A collection of actions applied when the time runs out.
+    #[serde(default)]
+    pub actions: Vec<Action>,
+    /// The label of the [`Batch`] or [`Prompt`] to jump to when the time runs out. If no label is
+    /// specified, the story simply moves on.
+    #[serde(alias = "jump")]
+    pub goto: Option<String>,
 }
 
 /// A prompt that may be presented to the player.
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "RawPrompt")]
 pub struct Prompt {
+    /// The label of this [`Prompt`], used to jump to it.
+    pub id: Option<String>,
     /// A pre-condition for this prompt. If the condition is evaluated to `false`, then this prompt
     /// is skipped. If no condition is specified, the prompt is presented.
     #[serde(rename = "if")]
     pub pre_condition: Option<Condition>,
-    /// The request string that'll be animated on the terminal.
-    pub request: String,
+    /// The phrasings of the request that'll be animated on the terminal, of which one is picked
+    /// every time the prompt is presented.
+    pub request: Vec<Variant>,
     /// The possible answers for this prompt.
     pub answers: Vec<Answer>,
+    /// What happens when the player does not answer.
+    pub on_timeout: Timeout,
+    /// How likely this prompt is to be drawn from its [`Batch`], relative to the other ones.
+    pub weight: f64,
+    /// A group of prompts of which only one is presented per game.
+    pub group: Option<String>,
+    /// Whether this prompt is presented at most once per game, even if it appears in several
+    /// batches.
+    pub once: bool,
+}
+
+impl Prompt {
+    /// The name that identifies this prompt across batches: its label, or its first request when
+    /// it has none.
+    pub fn key(&self) -> &str {
+        match (&self.id, self.request.first()) {
+            (Some(id), _) => id,
+            (None, Some(variant)) => &variant.text,
+            (None, None) => "",
+        }
+    }
+}
+
+/// A possible phrasing of the request of a [`Prompt`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Variant {
+    /// A condition for this variant to be picked. If no condition is specified, the variant can
+    /// always be picked.
+    #[serde(rename = "if")]
+    pub condition: Option<Condition>,
+    /// The text of the request.
+    pub text: String,
+    /// How likely this variant is to be picked, relative to the other ones.
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+}
+
+/// The request of a [`Prompt`] in the story file: a single text, or a list of variants.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawRequest {
+    Text(String),
+    Variants(Vec<Variant>),
+}
+
+/// The shape of a [`Prompt`] in the story file.
+///
+/// Older stories have no `on_timeout`: instead, their first answer has no text and is chosen when
+/// the player does not answer. Such answers are turned into a [`Timeout`], which can only hold
+/// their actions and their jump.
+#[derive(Deserialize)]
+struct RawPrompt {
+    id: Option<String>,
+    #[serde(rename = "if")]
+    pre_condition: Option<Condition>,
+    request: RawRequest,
+    answers: Vec<Answer>,
+    on_timeout: Option<Timeout>,
+    weight: Option<f64>,
+    group: Option<String>,
+    #[serde(default)]
+    once: bool,
+}
+
+impl TryFrom<RawPrompt> for Prompt {
+    type Error = &'static str;
+
+    fn try_from(mut raw: RawPrompt) -> Result<Self, Self::Error> {
+        let on_timeout = match raw.on_timeout {
+            Some(on_timeout) => on_timeout,
+            None => match raw.answers.first() {
+                Some(answer) if answer.text.is_empty() && answer.condition.is_none() => {
+                    let answer = raw.answers.remove(0);
+                    if answer.call.is_some() || !answer.outcomes.is_empty() {
+                        return Err(
+                            "an answer without text cannot have a `call` or `outcomes`, use `on_timeout` instead",
+                        );
+                    }
+                    Timeout {
+                        actions: answer.actions,
+                        goto: answer.goto,
+                    }
+                }
+                _ => Timeout::default(),
+            },
+        };
+
+        let request = match raw.request {
+            RawRequest::Text(text) => vec![Variant {
+                condition: None,
+                text,
+                weight: default_weight(),
+            }],
+            RawRequest::Variants(variants) => variants,
+        };
+
+        Ok(Self {
+            id: raw.id,
+            pre_condition: raw.pre_condition,
+            request,
+            answers: raw.answers,
+            on_timeout,
+            weight: raw.weight.unwrap_or_else(default_weight),
+            group: raw.group,
+            once: raw.once,
+        })
+    }
 }
 
 /// A batch of prompts.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Batch {
+    /// The label of this [`Batch`], used to jump to it.
+    pub id: Option<String>,
+    /// A condition for this batch to be played. If the condition is evaluated to `false` when the
+    /// story reaches the batch, the whole batch is skipped.
+    #[serde(rename = "if")]
+    pub condition: Option<Condition>,
+    /// A condition for this batch to be played again once it has been exhausted. If no condition
+    /// is specified, the batch is only played once.
+    pub repeat_while: Option<Condition>,
+    /// The number of prompts drawn from this batch each time it is played. If no number is
+    /// specified, every prompt is played.
+    pub draw: Option<usize>,
     /// Whether the prompts of this [`Batch`] can be randomized, or, on the contrary, whether they
     /// should be present in a fixed order.
-    #[serde(rename = "random")]
+    #[serde(rename = "random", default)]
     pub randomized: bool,
+    /// Whether this [`Batch`] is only played when it is called, queued or jumped to. Such batches
+    /// are skipped when the story moves on from the batch before them.
+    #[serde(default)]
+    pub callable: bool,
     /// The prompts that are part of this [`Batch`].
     pub prompts: Vec<Prompt>,
 }
 
+/// Something that happens when a [`Trigger`] fires.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Effect {
+    /// Ends the game immediately.
+    End,
+    /// Jumps to the [`Batch`] or [`Prompt`] with the given label.
+    #[serde(alias = "jump")]
+    Goto(String),
+    /// Plays the [`Batch`] or [`Prompt`] with the given label, then comes back to the prompt that
+    /// would have been presented next.
+    Call(String),
+    /// Plays the [`Batch`] or [`Prompt`] with the given label before the next prompt, whatever it
+    /// is. Unlike jumps and calls, several queued labels are played one after the other.
+    Queue(String),
+    /// Plays a sound, relative to the assets folder.
+    Play(String),
+}
+
+/// A global rule that's checked every time an [`Action`] is applied, and every time the player
+/// answers, once the variables maintained by the story are updated.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Trigger {
+    /// The trigger fires as soon as this condition becomes true.
+    #[serde(rename = "if")]
+    pub condition: Condition,
+    /// What happens when the trigger fires.
+    pub then: Vec<Effect>,
+    /// Whether the trigger only fires the first time its condition becomes true. Otherwise, it
+    /// fires every time.
+    #[serde(default)]
+    pub once: bool,
+}
+
+/// A possible ending of the story.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ending {
+    /// The condition for this ending to be chosen. If no condition is specified, the ending always
+    /// matches.
+    #[serde(rename = "if")]
+    pub condition: Option<Condition>,
+    /// The title of the ending.
+    pub title: String,
+    /// The text that'll be animated on the game over screen.
+    #[serde(default)]
+    pub text: String,
+    /// The image displayed behind the text, relative to the assets folder.
+    pub image: Option<String>,
+}
+
 /// The main story structure. This basically acts as a collection of [`Batch`]es.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Story {
+    /// The declarations of the variables used by the story.
+    #[serde(default)]
+    pub variables: HashMap<String, Declaration>,
+    /// Whether using a variable that has not been declared is an error.
+    #[serde(default)]
+    pub strict: bool,
     /// A collection of actions that should be taken at the begining of the game.
     pub actions: Vec<Action>,
+    /// The rules that are checked every time a variable is modified.
+    #[serde(default)]
+    pub triggers: Vec<Trigger>,
     /// The batches that are to be presented to the player before ending the game.
     pub batches: Vec<Batch>,
+    /// The possible endings of the story. The first one whose condition holds is chosen.
+    #[serde(default)]
+    pub endings: Vec<Ending>,
+    /// The random events that may interrupt the batches.
+    #[serde(default)]
+    pub events: Vec<Event>,
+    /// The position of every labelled [`Batch`] and [`Prompt`], filled by
+    /// [`Story::resolve_labels`].
+    #[serde(skip)]
+    pub labels: HashMap<String, Target>,
+}
+
+/// A random event that may interrupt the story after an answer, before the next prompt.
+///
+/// Once its prompts are exhausted, the story resumes where it was interrupted. The labels of an
+/// event can be called or queued, but not jumped to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Event {
+    /// The chance, between 0 and 1, that this event interrupts the story after an answer.
+    pub probability: f64,
+    /// The number of answers after this event during which it cannot happen again.
+    #[serde(default)]
+    pub cooldown: usize,
+    /// The prompts of this event. The event only happens when the condition of the batch holds.
+    #[serde(flatten)]
+    pub batch: Batch,
+}
+
+/// A position in the story that can be jumped to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Target {
+    /// The index of the [`Batch`].
+    pub batch: usize,
+    /// The index of the [`Prompt`] in its batch, or `None` to start the batch from its beginning.
+    pub prompt: Option<usize>,
+}
+
+/// An error in the labels of a story.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LabelError {
+    /// Two batches or prompts have the same label.
+    Duplicate(String),
+    /// A jump leads to a label that does not exist.
+    Unknown(String),
+    /// A jump leads to an event, which can only be called or queued.
+    Event(String),
+}
+
+impl fmt::Display for LabelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Duplicate(id) => write!(f, "the label `{id}` is used more than once"),
+            Self::Unknown(id) => write!(f, "no batch or prompt is labelled `{id}`"),
+            Self::Event(id) => write!(
+                f,
+                "`{id}` is part of an event, it can be called or queued but not jumped to"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LabelError {}
+
+/// An error in the variables used by a story.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VariableError {
+    /// A variable whose name has a dash is never declared nor modified: it is most likely a
+    /// subtraction written without spaces.
+    Unknown(String),
+    /// A variable is used without being declared, in strict mode.
+    Undeclared(String),
+    /// A variable is given or compared with a value of the wrong type, in strict mode.
+    Mismatch {
+        name: String,
+        expected: Type,
+        found: Type,
+    },
+}
+
+impl fmt::Display for VariableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown(name) => write!(
+                f,
+                "the variable `{name}` is never declared nor modified, \
+                 subtractions are written with spaces around `-`"
+            ),
+            Self::Undeclared(name) => write!(f, "undeclared variable `{name}`"),
+            Self::Mismatch {
+                name,
+                expected,
+                found,
+            } => write!(f, "`{name}` is a {expected}, but is used with a {found}"),
+        }
+    }
+}
+
+impl std::error::Error for VariableError {}
+
+/// The extensions of the sounds the game can play.
+const SOUND_EXTENSIONS: [&str; 2] = ["ogg", "wav"];
+
+/// A sound of the story that the game cannot play.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SoundError(pub String);
+
+impl fmt::Display for SoundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the sound `{}` is neither an `.ogg` nor a `.wav` file",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for SoundError {}
+
+impl Story {
+    /// Finds the position of every labelled batch and prompt, events included, and checks that
+    /// every jump of the story leads to one of them.
+    pub fn resolve_labels(&mut self) -> Result<(), LabelError> {
+        let mut labels = HashMap::new();
+        let events = self.events.iter().map(|event| &event.batch);
+        for (batch_index, batch) in self.batches.iter().chain(events).enumerate() {
+            let prompts = batch
+                .prompts
+                .iter()
+                .enumerate()
+                .filter_map(|(index, prompt)| Some((prompt.id.as_ref()?, Some(index))));
+
+            for (id, prompt) in batch.id.iter().map(|id| (id, None)).chain(prompts) {
+                let target = Target {
+                    batch: batch_index,
+                    prompt,
+                };
+                if labels.insert(id.clone(), target).is_some() {
+                    return Err(LabelError::Duplicate(id.clone()));
+                }
+            }
+        }
+
+        // Every label the story leads to, along with whether it is jumped to.
+        let events = self.events.iter().map(|event| &event.batch);
+        let answers = self
+            .batches
+            .iter()
+            .chain(events)
+            .flat_map(|batch| &batch.prompts)
+            .flat_map(|prompt| {
+                prompt
+                    .answers
+                    .iter()
+                    .flat_map(|answer| {
+                        let outcomes = answer
+                            .outcomes
+                            .iter()
+                            .filter_map(|outcome| outcome.goto.as_ref());
+                        let gotos = answer.goto.iter().chain(outcomes);
+                        let calls = answer.call.iter().map(|id| (id, false));
+                        gotos.map(|id| (id, true)).chain(calls)
+                    })
+                    .chain(prompt.on_timeout.goto.iter().map(|id| (id, true)))
+            });
+        let effects = self
+            .triggers
+            .iter()
+            .flat_map(|trigger| &trigger.then)
+            .filter_map(|effect| match effect {
+                Effect::Goto(id) => Some((id, true)),
+                Effect::Call(id) | Effect::Queue(id) => Some((id, false)),
+                _ => None,
+            });
+
+        for (id, jump) in answers.chain(effects) {
+            match labels.get(id) {
+                None => return Err(LabelError::Unknown(id.clone())),
+                Some(target) if jump && target.batch >= self.batches.len() => {
+                    return Err(LabelError::Event(id.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+
+        self.labels = labels;
+        Ok(())
+    }
+
+    /// The variables maintained by the story itself, with their initial value:
+    ///
+    /// - `_timeouts`: the number of prompts the player did not answer in time.
+    /// - `_reaction_time`: the time the player took to settle on their last answer, in seconds.
+    /// - `_hesitations`: the number of times the player changed their last answer after first
+    ///   choosing one.
+    /// - `_answer-<id>`: the number of the answer chosen at the labelled prompt `<id>`, starting
+    ///   at 1. It is 0 until the prompt is answered, and -1 when the time ran out.
+    /// - `_text-<id>`: the text of that answer.
+    pub fn builtins(&self) -> Vec<(String, Value)> {
+        let events = self.events.iter().map(|event| &event.batch);
+        let labels = self
+            .batches
+            .iter()
+            .chain(events)
+            .flat_map(|batch| &batch.prompts)
+            .filter_map(|prompt| prompt.id.as_ref());
+
+        let mut builtins = vec![
+            ("_timeouts".to_owned(), Value::Int(0)),
+            ("_reaction_time".to_owned(), Value::Float(0.0)),
+            ("_hesitations".to_owned(), Value::Int(0)),
+        ];
+        for id in labels {
+            builtins.push((format!("_answer-{id}"), Value::Int(0)));
+            builtins.push((format!("_text-{id}"), Value::Text(String::new())));
+        }
+        builtins
+    }
+
+    /// Every prompt of the story, in its batches and its events.
+    fn prompts(&self) -> impl Iterator<Item = &Prompt> {
+        let events = self.events.iter().map(|event| &event.batch);
+        self.batches
+            .iter()
+            .chain(events)
+            .flat_map(|batch| &batch.prompts)
+    }
+
+    /// Every action of the story.
+    fn every_action(&self) -> Vec<&Action> {
+        let mut actions: Vec<&Action> = self.actions.iter().collect();
+        for prompt in self.prompts() {
+            actions.extend(&prompt.on_timeout.actions);
+            for answer in &prompt.answers {
+                actions.extend(&answer.actions);
+                actions.extend(answer.outcomes.iter().flat_map(|outcome| &outcome.actions));
+            }
+        }
+        actions
+    }
+
+    /// Every condition of the story. Conditions nested in other conditions are not listed.
+    fn every_condition(&self) -> Vec<&Condition> {
+        let events = self.events.iter().map(|event| &event.batch);
+        let mut conditions = Vec::new();
+        for batch in self.batches.iter().chain(events) {
+            conditions.extend(&batch.condition);
+            conditions.extend(&batch.repeat_while);
+        }
+        for prompt in self.prompts() {
+            conditions.extend(&prompt.pre_condition);
+            conditions.extend(prompt.request.iter().flat_map(|variant| &variant.condition));
+            conditions.extend(prompt.answers.iter().flat_map(|answer| &answer.condition));
+        }
+        conditions.extend(self.triggers.iter().map(|trigger| &trigger.condition));
+        conditions.extend(self.endings.iter().flat_map(|ending| &ending.condition));
+        conditions
+    }
+
+    /// The names of the variables that are declared or maintained by the story and, unless in
+    /// strict mode, the ones modified by an action.
+    fn known_variables(&self) -> HashSet<String> {
+        let mut known: HashSet<String> = self.variables.keys().cloned().collect();
+        known.extend(self.builtins().into_iter().map(|(name, _)| name));
+        if !self.strict {
+            known.extend(self.every_action().iter().map(|action| action.name.clone()));
+        }
+        known
+    }
+
+    /// Checks the variables used by the actions and the conditions of the story.
+    ///
+    /// In strict mode, every variable must be declared and used with values of its type.
+    pub fn check_variables(&self) -> Result<(), VariableError> {
+        let known = self.known_variables();
+        let mut names = Vec::new();
+        for action in self.every_action() {
+            if self.strict {
+                names.push(action.name.as_str());
+            }
+            action.value.variables(&mut names);
+        }
+        for condition in self.every_condition() {
+            condition.variables(&mut names);
+        }
+
+        let unknown = names
+            .into_iter()
+            .find(|name| !known.contains(*name) && (self.strict || name.contains('-')));
+        match unknown {
+            Some(name) if self.strict => Err(VariableError::Undeclared(name.to_owned())),
+            Some(name) => Err(VariableError::Unknown(name.to_owned())),
+            None if self.strict => self.check_types(),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that the actions and the conditions of a strict story use values of the type of
+    /// their variable, when it can be known before the story is played.
+    fn check_types(&self) -> Result<(), VariableError> {
+        let mut types: HashMap<String, Type> = self
+            .builtins()
+            .into_iter()
+            .map(|(name, value)| (name, value.ty()))
+            .collect();
+        types.extend(
+            self.variables
+                .iter()
+                .map(|(name, declaration)| (name.clone(), declaration.ty)),
+        );
+        let lookup = |name: &str| types.get(name).copied();
+        let operands = |operand: &Operand| match operand {
+            Operand::Single(expr) => vec![expr.ty(&lookup)],
+            Operand::List(exprs) => exprs.iter().map(|expr| expr.ty(&lookup)).collect(),
+        };
+        let mismatch = |name: &str, expected: Type, found: Type| VariableError::Mismatch {
+            name: name.to_owned(),
+            expected,
+            found,
+        };
+
+        for action in self.every_action() {
+            let expected = match lookup(&action.name) {
+                Some(ty) => ty,
+                None => continue,
+            };
+            let arithmetic = match action.op {
+                Operation::Add => Some(BinOp::Add),
+                Operation::Sub => Some(BinOp::Sub),
+                Operation::Mul => Some(BinOp::Mul),
+                Operation::Div => Some(BinOp::Div),
+                _ => None,
+            };
+
+            // The type of the new value of the variable. `min`, `max` and `clamp` keep it.
+            let found = match (action.op, operands(&action.value).as_slice()) {
+                (Operation::Set, [ty]) => *ty,
+                (_, [Some(ty)]) => arithmetic.map(|op| op.result(expected, *ty).unwrap_or(*ty)),
+                (Operation::Random, [Some(Type::Int), Some(Type::Int)]) => Some(Type::Int),
+                (Operation::Random, [Some(min), Some(max)]) => {
+                    BinOp::Add.result(*min, *max).or(Some(*min))
+                }
+                _ => None,
+            };
+            if let Some(found) = found.filter(|&found| !expected.accepts(found)) {
+                return Err(mismatch(&action.name, expected, found));
+            }
+        }
+
+        let mut comparisons = Vec::new();
+        for condition in self.every_condition() {
+            condition.comparisons(&mut comparisons);
+        }
+        for comparison in comparisons {
+            let expected = match lookup(&comparison.name) {
+                Some(ty) => ty,
+                None => continue,
+            };
+            let found = operands(&comparison.value)
+                .into_iter()
+                .flatten()
+                .find(|&found| !expected.comparable(found));
+            if let Some(found) = found {
+                return Err(mismatch(&comparison.name, expected, found));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every text of the story is a valid [`Template`] whose placeholders only use
+    /// variables that are declared or maintained by the story or, unless in strict mode, modified
+    /// by an action.
+    pub fn check_texts(&self) -> Result<(), TextError> {
+        let known = self.known_variables();
+        let mut texts = Vec::new();
+        for prompt in self.prompts() {
+            texts.extend(prompt.request.iter().map(|variant| &variant.text));
+            for answer in &prompt.answers {
+                texts.push(&answer.text);
+                texts.extend(answer.outcomes.iter().flat_map(|outcome| &outcome.text));
+            }
+        }
+        for ending in &self.endings {
+            texts.push(&ending.title);
+            texts.push(&ending.text);
+        }
+
+        for text in texts {
+            let error = |message: String| TextError {
+                text: text.clone(),
+                message,
+            };
+            let template: Template = text
+                .parse()
+                .map_err(|err: ParseError| error(err.to_string()))?;
+            for name in template.names() {
+                if !known.contains(name) {
+                    return Err(error(format!("unknown variable `{name}`")));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every sound played by the story has a format the game can decode.
+    pub fn check_sounds(&self) -> Result<(), SoundError> {
+        let sounds = self
+            .triggers
+            .iter()
+            .flat_map(|trigger| &trigger.then)
+            .filter_map(|effect| match effect {
+                Effect::Play(sound) => Some(sound),
+                _ => None,
+            });
+
+        for sound in sounds {
+            let supported = match sound.rsplit_once('.') {
+                Some((_, extension)) => SOUND_EXTENSIONS
+                    .iter()
+                    .any(|supported| extension.eq_ignore_ascii_case(supported)),
+                None => false,
+            };
+            if !supported {
+                return Err(SoundError(sound.clone()));
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
 /// Parses a [`Story`] instance at assets/story.json
 pub fn parse_story() -> io::Result<Story> {
     let file = BufReader::new(File::open("assets/story.json")?);
-    let story = serde_json::from_reader(file)?;
-    Ok(story)
+    validate(serde_json::from_reader(file)?)
 }
 
 #[cfg(any(not(debug_assertions), target_arch = "wasm32"))]
 /// Parses a [`Story`] instance directly loaded from assets/story.json
 pub fn parse_story() -> io::Result<Story> {
     let file = include_str!("../../assets/story.json");
-    let story = serde_json::from_str(file)?;
+    validate(serde_json::from_str(file)?)
+}
+
+/// Resolves the labels of a freshly loaded [`Story`], and checks its variables, texts and sounds.
+fn validate(mut story: Story) -> io::Result<Story> {
+    story
+        .resolve_labels()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    story
+        .check_variables()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    story
+        .check_texts()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    story
+        .check_sounds()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
     Ok(story)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn story(json: &str) -> Story {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn sounds() {
+        let play = |sound: &str| {
+            story(&format!(
+                r#"{{
+                    "actions": [],
+                    "triggers": [{{ "if": {{ "name": "a", "op": "equal", "value": 1 }}, "then": [{{ "play": "{sound}" }}] }}],
+                    "batches": []
+                }}"#
+            ))
+            .check_sounds()
+        };
+        assert_eq!(play("SFX/alarm.wav"), Ok(()));
+        assert_eq!(play("SFX/alarm.OGG"), Ok(()));
+        assert_eq!(
+            play("SFX/alarm.mp3"),
+            Err(SoundError("SFX/alarm.mp3".into()))
+        );
+        assert_eq!(play("SFX/alarm"), Err(SoundError("SFX/alarm".into())));
+    }
+
+    #[test]
+    fn comparison_operands() {
+        let condition =
+            |json: &str| serde_json::from_str::<Condition>(json).map_err(|err| err.to_string());
+        assert!(condition(r#"{ "name": "a", "op": "between", "value": [1, 5] }"#).is_ok());
+        assert!(condition(r#"{ "name": "a", "op": "one-of", "value": [1, 2, 3] }"#).is_ok());
+        assert!(condition(r#"{ "name": "a", "op": "one-of", "value": 1 }"#).is_ok());
+        assert!(condition(r#"{ "name": "a", "op": "equal", "value": [1] }"#).is_ok());
+
+        let error = condition(r#"{ "name": "a", "op": "between", "value": 1 }"#).unwrap_err();
+        assert!(error.starts_with("`between` expects two values"), "{error}");
+        let error = condition(r#"{ "name": "a", "op": "equal", "value": [1, 2] }"#).unwrap_err();
+        assert!(
+            error.starts_with("`equal` expects a single value"),
+            "{error}"
+        );
+        let error = condition(r#"{ "name": "a", "op": "one-of", "value": [] }"#).unwrap_err();
+        assert!(
+            error.starts_with("`one-of` expects at least one value"),
+            "{error}"
+        );
+        let error = condition(r#"{ "not": { "name": "a", "op": "less-or-equal", "value": [] } }"#)
+            .unwrap_err();
+        assert!(
+            error.starts_with("`less-or-equal` expects a single value"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn action_operands() {
+        let action =
+            |json: &str| serde_json::from_str::<Action>(json).map_err(|err| err.to_string());
+        assert!(action(r#"{ "name": "a", "op": "clamp", "value": [0, 10] }"#).is_ok());
+        assert!(action(r#"{ "name": "a", "op": "add", "value": "b + 1", "delay": 2 }"#).is_ok());
+
+        let error = action(r#"{ "name": "a", "op": "random", "value": 3 }"#).unwrap_err();
+        assert!(error.starts_with("`random` expects two values"), "{error}");
+        let error = action(r#"{ "name": "a", "op": "clamp", "value": [0, 1, 2] }"#).unwrap_err();
+        assert!(error.starts_with("`clamp` expects two values"), "{error}");
+        let error = action(r#"{ "name": "a", "op": "set", "value": [1, 2] }"#).unwrap_err();
+        assert!(error.starts_with("`set` expects a single value"), "{error}");
+    }
+}
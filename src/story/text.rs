@@ -0,0 +1,300 @@
+//! Texts of the story that show the values of variables.
+//!
+//! A placeholder such as `{crewmate_count}` is replaced by the value of a variable. Numbers can be
+//! given a number of decimals: `{morale:.1}`. Plural forms are chosen with
+//! `{crewmate_count|crewmate|crewmates}`, where `#` stands for the value: `{count|# day|# days}`.
+//! Braces are written `{{` and `}}`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::{ParseError, Value, Variables};
+
+/// A placeholder replaced by the value of a variable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Placeholder {
+    name: String,
+    /// The number of decimals of numbers.
+    precision: Option<usize>,
+    /// The singular and plural forms, shown instead of the value.
+    forms: Option<(String, String)>,
+}
+
+impl Placeholder {
+    fn render(&self, value: &Value) -> String {
+        let formatted = match (self.precision, value.as_f64()) {
+            (Some(precision), Some(number)) => format!("{number:.precision$}"),
+            _ => value.to_string(),
+        };
+
+        match &self.forms {
+            Some((one, many)) => {
+                let form = if value.as_f64() == Some(1.0) {
+                    one
+                } else {
+                    many
+                };
+                form.replace('#', &formatted)
+            }
+            None => formatted,
+        }
+    }
+}
+
+/// A part of a [`Template`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// A text with placeholders.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Iterates over the names of the variables used by this template.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Placeholder(placeholder) => Some(placeholder.name.as_str()),
+            Segment::Text(_) => None,
+        })
+    }
+
+    /// Replaces the placeholders with the values of the variables. Placeholders that cannot be
+    /// evaluated are reported and left as they are.
+    pub fn render(&self, variables: &Variables) -> String {
+        let mut text = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(part) => text.push_str(part),
+                Segment::Placeholder(placeholder) => match variables.get(&placeholder.name) {
+                    Ok(value) => text.push_str(&placeholder.render(value)),
+                    Err(err) => {
+                        eprintln!("error: {err}");
+                        text.push_str(&format!("{{{}}}", placeholder.name));
+                    }
+                },
+            }
+        }
+        text
+    }
+}
+
+impl FromStr for Template {
+    type Err = ParseError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let error = |position: usize, message: &str| ParseError {
+            position,
+            message: message.into(),
+        };
+
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.char_indices().peekable();
+        while let Some((position, c)) = chars.next() {
+            match c {
+                '{' if chars.peek().map(|&(_, c)| c) == Some('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek().map(|&(_, c)| c) == Some('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '}' => return Err(error(position, "unexpected `}`, write `}}` instead")),
+                '{' => {
+                    let end = match source[position..].find('}') {
+                        Some(end) => position + end,
+                        None => return Err(error(position, "unclosed placeholder")),
+                    };
+                    while matches!(chars.peek(), Some(&(index, _)) if index <= end) {
+                        chars.next();
+                    }
+
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    let placeholder = parse_placeholder(&source[position + 1..end])
+                        .map_err(|message| error(position, message))?;
+                    segments.push(Segment::Placeholder(placeholder));
+                }
+                c => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        Ok(Self { segments })
+    }
+}
+
+/// Parses the inside of a placeholder: `name`, `name:.2` or `name|one|many`.
+fn parse_placeholder(source: &str) -> Result<Placeholder, &'static str> {
+    let mut parts = source.split('|');
+    let head = parts.next().unwrap_or_default().trim();
+    let forms = match (parts.next(), parts.next(), parts.next()) {
+        (None, _, _) => None,
+        (Some(one), Some(many), None) => Some((one.to_owned(), many.to_owned())),
+        _ => return Err("expected a singular and a plural form"),
+    };
+
+    let (name, precision) = match head.split_once(':') {
+        Some((name, format)) => {
+            let precision = format
+                .trim()
+                .strip_prefix('.')
+                .and_then(|digits| digits.parse().ok())
+                .ok_or("expected a number of decimals, such as `:.2`")?;
+            (name.trim(), Some(precision))
+        }
+        None => (head, None),
+    };
+
+    let valid = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    if name.is_empty() || !name.chars().all(valid) {
+        return Err("expected the name of a variable");
+    }
+
+    Ok(Placeholder {
+        name: name.to_owned(),
+        precision,
+        forms,
+    })
+}
+
+/// Fills the placeholders of a text with the values of the variables. Texts that are not valid
+/// templates are reported and left as they are.
+pub fn render(text: &str, variables: &Variables) -> String {
+    match text.parse::<Template>() {
+        Ok(template) => template.render(variables),
+        Err(err) => {
+            eprintln!("error: {err}");
+            text.to_owned()
+        }
+    }
+}
+
+/// An error in a text of the story.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextError {
+    /// The text in which the error occured.
+    pub text: String,
+    /// What went wrong.
+    pub message: String,
+}
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in \"{}\"", self.message, self.text)
+    }
+}
+
+impl std::error::Error for TextError {}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn variables() -> Variables {
+        let mut variables = Variables::default();
+        variables.set("one", Value::Int(1)).unwrap();
+        variables.set("crew", Value::Int(3)).unwrap();
+        variables.set("morale", Value::Float(2.345)).unwrap();
+        variables.set("captain", Value::Text("Ann".into())).unwrap();
+        variables.set("_answer-a", Value::Int(2)).unwrap();
+        variables
+    }
+
+    fn error(source: &str) -> ParseError {
+        source.parse::<Template>().unwrap_err()
+    }
+
+    #[test]
+    fn placeholders() {
+        let variables = variables();
+        assert_eq!(render("Hello, {captain}.", &variables), "Hello, Ann.");
+        assert_eq!(render("{crew}/{ crew }", &variables), "3/3");
+        assert_eq!(render("{morale}", &variables), "2.345");
+        assert_eq!(render("{_answer-a}", &variables), "2");
+        assert_eq!(render("{unset}", &variables), "0");
+        assert_eq!(render("no placeholder", &variables), "no placeholder");
+    }
+
+    #[test]
+    fn escapes() {
+        let variables = variables();
+        assert_eq!(render("{{crew}}", &variables), "{crew}");
+        assert_eq!(render("{{{crew}}}", &variables), "{3}");
+        assert_eq!(render("}}{{", &variables), "}{");
+    }
+
+    #[test]
+    fn precision() {
+        let variables = variables();
+        assert_eq!(render("{morale:.1}", &variables), "2.3");
+        assert_eq!(render("{morale:.0}", &variables), "2");
+        assert_eq!(render("{crew:.2}", &variables), "3.00");
+        assert_eq!(render("{captain:.2}", &variables), "Ann");
+    }
+
+    #[test]
+    fn plurals() {
+        let variables = variables();
+        assert_eq!(render("{one|# day|# days}", &variables), "1 day");
+        assert_eq!(render("{crew|# day|# days}", &variables), "3 days");
+        assert_eq!(render("{unset|# day|# days}", &variables), "0 days");
+        assert_eq!(render("{crew|it|them}", &variables), "them");
+        assert_eq!(
+            render("{morale:.1|# knot|# knots}", &variables),
+            "2.3 knots"
+        );
+    }
+
+    #[test]
+    fn names() {
+        let template: Template = "{crew} and {morale:.1|#|#} {{not}}".parse().unwrap();
+        assert_eq!(template.names().collect::<Vec<_>>(), ["crew", "morale"]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("a {crew").message, "unclosed placeholder");
+        assert_eq!(error("a {crew").position, 2);
+        assert_eq!(error("a } b").position, 2);
+        assert_eq!(error("é}").position, 2);
+        assert_eq!(
+            error("{crew|day}").message,
+            "expected a singular and a plural form"
+        );
+        assert_eq!(
+            error("x {crew|a|b|c}").message,
+            "expected a singular and a plural form"
+        );
+        assert_eq!(error("x {crew|a|b|c}").position, 2);
+        for source in ["{crew:2}", "{crew:.}", "{crew:.x}"] {
+            assert_eq!(
+                error(source).message,
+                "expected a number of decimals, such as `:.2`"
+            );
+        }
+        for source in ["{}", "{ }", "{a b}", "{:.2}", "{a+b}"] {
+            assert_eq!(error(source).message, "expected the name of a variable");
+        }
+    }
+
+    #[test]
+    fn invalid_texts() {
+        let variables = variables();
+        assert_eq!(render("{crew", &variables), "{crew");
+
+        let strict = Variables::new(&HashMap::new(), true);
+        assert_eq!(render("{crew} left", &strict), "{crew} left");
+    }
+}
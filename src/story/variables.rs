@@ -1,19 +1,298 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// The type of a variable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Type {
+    Int,
+    Float,
+    Bool,
+    String,
+}
+
+impl Type {
+    /// The value of a declared variable that has no explicit default.
+    pub fn default_value(self) -> Value {
+        match self {
+            Self::Int => Value::Int(0),
+            Self::Float => Value::Float(0.0),
+            Self::Bool => Value::Bool(false),
+            Self::String => Value::Text(String::new()),
+        }
+    }
+
+    /// Returns whether a variable of this type can be given a value of type `other`. Floats
+    /// accept integers.
+    pub fn accepts(self, other: Self) -> bool {
+        self == other || (self == Self::Float && other == Self::Int)
+    }
+
+    /// Returns whether values of this type can be compared with values of type `other`.
+    pub fn comparable(self, other: Self) -> bool {
+        self.accepts(other) || other.accepts(self)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Int => "int",
+            Self::Float => "float",
+            Self::Bool => "bool",
+            Self::String => "string",
+        })
+    }
+}
+
+/// The value of a variable.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl Value {
+    /// Returns the type of this value.
+    pub fn ty(&self) -> Type {
+        match self {
+            Self::Bool(_) => Type::Bool,
+            Self::Int(_) => Type::Int,
+            Self::Float(_) => Type::Float,
+            Self::Text(_) => Type::String,
+        }
+    }
+
+    /// Returns this value as a float, if it is a number.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Int(value) => Some(*value as f64),
+            Self::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Compares two values. Integers and floats can be compared with each other, other values
+    /// can only be compared with values of the same type.
+    pub fn compare(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Self::Int(a), Self::Int(b)) => Some(a.cmp(b)),
+            (Self::Bool(a), Self::Bool(b)) => Some(a.cmp(b)),
+            (Self::Text(a), Self::Text(b)) => Some(a.cmp(b)),
+            _ => self.as_f64()?.partial_cmp(&other.as_f64()?),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bool(value) => write!(f, "{value}"),
+            Self::Int(value) => write!(f, "{value}"),
+            Self::Float(value) => write!(f, "{value:?}"),
+            Self::Text(value) => f.write_str(value),
+        }
+    }
+}
+
+/// An error that occured while evaluating an expression or modifying a variable.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvalError {
+    /// A variable was used without being declared, in strict mode.
+    Undeclared(String),
+    /// A variable was assigned a value of the wrong type.
+    Mismatch {
+        name: String,
+        expected: Type,
+        found: Type,
+    },
+    /// An operator or a function was used on values it does not support.
+    Operands(&'static str, Vec<Type>),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Undeclared(name) => write!(f, "undeclared variable `{name}`"),
+            Self::Mismatch {
+                name,
+                expected,
+                found,
+            } => write!(f, "`{name}` is a {expected}, but was given a {found}"),
+            Self::Operands(op, types) => {
+                write!(f, "`{op}` cannot be used on ")?;
+                for (i, ty) in types.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{ty}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// The declaration of a variable in the story file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "RawDeclaration")]
+pub struct Declaration {
+    /// The type of the variable.
+    #[serde(rename = "type")]
+    pub ty: Type,
+    /// The initial value of the variable. The default value of the type is used when it is not
+    /// set.
+    pub default: Option<Value>,
+    /// The smallest value a number can take. Smaller values are clamped.
+    pub min: Option<f64>,
+    /// The largest value a number can take. Larger values are clamped.
+    pub max: Option<f64>,
+}
+
+impl Declaration {
+    /// Converts `value` to the type of the declared variable `name`, then clamps it.
+    fn constrain(&self, name: &str, value: Value) -> Result<Value, EvalError> {
+        let value = match (self.ty, value) {
+            (Type::Int, Value::Int(value)) => Value::Int(value),
+            (Type::Float, Value::Int(value)) => Value::Float(value as f64),
+            (Type::Float, Value::Float(value)) => Value::Float(value),
+            (Type::Bool, Value::Bool(value)) => Value::Bool(value),
+            (Type::String, Value::Text(value)) => Value::Text(value),
+            (expected, value) => {
+                return Err(EvalError::Mismatch {
+                    name: name.into(),
+                    expected,
+                    found: value.ty(),
+                })
+            }
+        };
+
+        Ok(match value {
+            Value::Int(mut value) => {
+                if let Some(min) = self.min {
+                    value = value.max(min.ceil() as i64);
+                }
+                if let Some(max) = self.max {
+                    value = value.min(max.floor() as i64);
+                }
+                Value::Int(value)
+            }
+            Value::Float(mut value) => {
+                if let Some(min) = self.min {
+                    value = value.max(min);
+                }
+                if let Some(max) = self.max {
+                    value = value.min(max);
+                }
+                Value::Float(value)
+            }
+            value => value,
+        })
+    }
+}
+
+/// The shape of a [`Declaration`] in the story file, before its default value is checked.
+#[derive(Deserialize)]
+struct RawDeclaration {
+    #[serde(rename = "type")]
+    ty: Type,
+    default: Option<Value>,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl TryFrom<RawDeclaration> for Declaration {
+    type Error = EvalError;
+
+    fn try_from(raw: RawDeclaration) -> Result<Self, Self::Error> {
+        let mut declaration = Self {
+            ty: raw.ty,
+            default: None,
+            min: raw.min,
+            max: raw.max,
+        };
+        if let Some(default) = raw.default {
+            declaration.default = Some(declaration.constrain("default", default)?);
+        }
+        Ok(declaration)
+    }
+}
 
 /// A collection of variables available for story scripting.
 #[derive(Debug, Clone, Default)]
 pub struct Variables {
-    variables: HashMap<String, i64>,
+    variables: BTreeMap<String, Value>,
+    declarations: HashMap<String, Declaration>,
+    /// Whether using an undeclared variable is an error.
+    strict: bool,
 }
 
+/// The value of variables that have never been set.
+static UNSET: Value = Value::Int(0);
+
 impl Variables {
+    /// Creates a new collection of variables, initialized to their declared default.
+    pub fn new(declarations: &HashMap<String, Declaration>, strict: bool) -> Self {
+        let variables = declarations
+            .iter()
+            .map(|(name, declaration)| {
+                let value = declaration
+                    .default
+                    .clone()
+                    .unwrap_or_else(|| declaration.ty.default_value());
+                (name.clone(), value)
+            })
+            .collect();
+
+        Self {
+            variables,
+            declarations: declarations.clone(),
+            strict,
+        }
+    }
+
     /// Gets the value of a specific variable.
-    pub fn get(&self, name: &str) -> i64 {
-        self.variables.get(name).copied().unwrap_or_default()
+    ///
+    /// Unless in strict mode, variables that have never been set are `0`.
+    pub fn get(&self, name: &str) -> Result<&Value, EvalError> {
+        match self.variables.get(name) {
+            Some(value) => Ok(value),
+            None if self.strict => Err(EvalError::Undeclared(name.into())),
+            None => Ok(&UNSET),
+        }
+    }
+
+    /// Iterates over every variable that has been declared or set, sorted by name.
+    #[cfg(any(debug_assertions, test))]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.variables
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// Sets the value of a specific variable, converting it to its declared type and clamping it
+    /// within its declared range.
+    pub fn set(&mut self, name: &str, value: Value) -> Result<(), EvalError> {
+        let value = match self.declarations.get(name) {
+            Some(declaration) => declaration.constrain(name, value)?,
+            None if self.strict => return Err(EvalError::Undeclared(name.into())),
+            None => value,
+        };
+        self.variables.insert(name.into(), value);
+        Ok(())
     }
 
-    /// Gets an exclusive reference to a specific variable.
-    pub fn get_mut(&mut self, name: &str) -> &mut i64 {
-        self.variables.entry(name.into()).or_default()
+    /// Sets the value of a variable maintained by the story itself. Such variables are never
+    /// declared, even in strict mode, and are not constrained.
+    pub fn set_builtin(&mut self, name: &str, value: Value) {
+        self.variables.insert(name.into(), value);
     }
 }
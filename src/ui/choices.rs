@@ -21,7 +21,7 @@ pub struct ChoiceBundle {
 
 /// A **component** that that is added to buttons.
 ///
-/// The index of the selected choice is stored in this component.
+/// The index of the answer shown by the button is stored in this component.
 #[derive(Component, Default, Debug)]
 pub struct Choice(pub usize);
 
@@ -37,6 +37,19 @@ impl<T: Component + Clone> Prev<T> {
     }
 }
 
+/// The keys that select an answer directly, in the order the answers are shown.
+const NUMBER_KEYS: [KeyCode; 9] = [
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+];
+
 impl Choice {
     #[allow(clippy::type_complexity)]
     pub fn select_choice_system(
@@ -52,12 +65,46 @@ impl Choice {
                 (*interaction, prev.0),
                 (Interaction::Hovered, Interaction::Clicked)
             ) {
-                if selected_choice.0 == choice.0 {
-                    selected_choice.0 = 0;
+                if selected_choice.0 == Some(choice.0) {
+                    selected_choice.0 = None;
                 } else {
-                    selected_choice.0 = choice.0;
+                    selected_choice.0 = Some(choice.0);
                 }
             }
         }
     }
+
+    /// A **system** that selects answers with the keyboard. The up and down arrows move the
+    /// selection, and the number keys select an answer directly.
+    pub fn keyboard_select_system(
+        query: Query<&Choice>,
+        keys: Res<Input<KeyCode>>,
+        mut selected_choice: ResMut<CurrentSelection>,
+    ) {
+        // Answers are shown in the order of their index.
+        let mut choices: Vec<usize> = query.iter().map(|choice| choice.0).collect();
+        choices.sort_unstable();
+        if choices.is_empty() {
+            return;
+        }
+
+        let count = choices.len();
+        let current = choices
+            .iter()
+            .position(|&choice| Some(choice) == selected_choice.0);
+        let next = if keys.just_pressed(KeyCode::Down) {
+            Some(current.map_or(0, |i| (i + 1) % count))
+        } else if keys.just_pressed(KeyCode::Up) {
+            Some(current.map_or(count - 1, |i| (i + count - 1) % count))
+        } else {
+            NUMBER_KEYS
+                .iter()
+                .position(|&key| keys.just_pressed(key))
+                .filter(|&i| i < count)
+        };
+
+        if let Some(next) = next {
+            selected_choice.0 = Some(choices[next]);
+        }
+    }
 }